@@ -0,0 +1,12 @@
+-- Emails are unique regardless of case, so concurrent registrations cannot both succeed.
+--
+-- Creating the index fails while two accounts share an email up to case. List them with
+--
+--   SELECT lower(email), array_agg(uuid ORDER BY created_at)
+--   FROM users GROUP BY lower(email) HAVING count(*) > 1;
+--
+-- and for each group keep the account its owner actually uses (usually the oldest), then
+-- change the email of the others to an address their owners confirm, or delete those
+-- accounts together with their rows in user_sessions. Apply this file once none are left.
+create unique index users_email_lower_key
+    on users (lower(email));
@@ -0,0 +1,5 @@
+-- The client a session was created from, for binding sessions with SESSION_BIND.
+-- Sessions created before this migration have neither and are not bound.
+alter table user_sessions
+    add column ip         text,
+    add column user_agent text;
@@ -0,0 +1,9 @@
+-- Identities of an OIDC provider linked to accounts, for /student/link_sso and /student/login_sso.
+create table external_identities
+(
+    issuer     text                     NOT NULL,
+    subject    text                     NOT NULL,
+    belongs_to uuid                     NOT NULL,
+    linked_at  timestamp WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (issuer, subject)
+);
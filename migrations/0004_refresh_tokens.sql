@@ -0,0 +1,8 @@
+-- Refresh tokens issued alongside access tokens when AUTH_MODE=jwt.
+create table refresh_tokens
+(
+    token      text                     NOT NULL
+        PRIMARY KEY,
+    belongs_to uuid                     NOT NULL,
+    expires_at timestamp WITH TIME ZONE NOT NULL
+);
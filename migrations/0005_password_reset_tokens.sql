@@ -0,0 +1,8 @@
+-- Single-use tokens emailed by /student/request_password_reset.
+create table password_reset_tokens
+(
+    token      text                     NOT NULL
+        PRIMARY KEY,
+    belongs_to uuid                     NOT NULL,
+    expires_at timestamp WITH TIME ZONE NOT NULL
+);
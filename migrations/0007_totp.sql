@@ -0,0 +1,19 @@
+-- TOTP secrets of students enrolling in or using two-factor authentication. It is only
+-- enforced once confirmed.
+create table user_totp
+(
+    belongs_to     uuid                     NOT NULL
+        PRIMARY KEY,
+    secret         text                     NOT NULL,
+    confirmed      boolean                  NOT NULL DEFAULT false,
+    last_used_step bigint,
+    created_at     timestamp WITH TIME ZONE NOT NULL
+);
+
+-- Hashes of the single-use recovery codes handed out on confirming two-factor authentication.
+create table totp_recovery_codes
+(
+    belongs_to uuid NOT NULL,
+    code_hash  text NOT NULL,
+    PRIMARY KEY (belongs_to, code_hash)
+);
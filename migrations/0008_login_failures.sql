@@ -0,0 +1,8 @@
+-- Consecutive failed logins per account, and until when it is locked after too many.
+create table login_failures
+(
+    belongs_to   uuid    NOT NULL
+        PRIMARY KEY,
+    failures     integer NOT NULL,
+    locked_until timestamp WITH TIME ZONE
+);
@@ -0,0 +1,5 @@
+-- Roles users act in. Everyone registered so far is a student.
+create type user_role as enum ('student', 'teacher', 'admin');
+
+alter table users
+    add column role user_role NOT NULL DEFAULT 'student';
@@ -0,0 +1,7 @@
+-- What teachers registered through /teacher/register teach.
+create table teacher_profiles
+(
+    belongs_to uuid   NOT NULL
+        PRIMARY KEY,
+    subjects   text[] NOT NULL
+);
@@ -0,0 +1,20 @@
+-- Parent accounts, linked to their children through codes the school hands out.
+alter type user_role add value 'parent';
+
+create table parent_children
+(
+    parent_id uuid                     NOT NULL,
+    child_id  uuid                     NOT NULL,
+    linked_at timestamp WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (parent_id, child_id)
+);
+
+-- Single-use codes issued by teachers or admins, redeemed by a parent to link to child_id.
+create table parent_link_codes
+(
+    code       text                     NOT NULL
+        PRIMARY KEY,
+    child_id   uuid                     NOT NULL,
+    issued_by  uuid                     NOT NULL,
+    expires_at timestamp WITH TIME ZONE NOT NULL
+);
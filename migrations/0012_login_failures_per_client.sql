@@ -0,0 +1,8 @@
+-- Failed logins are counted per client address, so one client guessing a password
+-- cannot lock everyone else out of the account. Earlier counts stay with an unknown client.
+alter table login_failures
+    add column client text NOT NULL default '';
+alter table login_failures
+    alter column client drop default,
+    drop constraint login_failures_pkey,
+    add PRIMARY KEY (belongs_to, client);
@@ -1,3 +1,8 @@
+-- Full schema for new databases. Databases created from an earlier version are brought up
+-- to date by applying the files in migrations/ they are missing, in order.
+
+create type user_role as enum ('student', 'teacher', 'admin', 'parent');
+
 create table users
 (
     uuid          uuid                     NOT NULL
@@ -9,13 +14,95 @@ create table users
     patronymic    text,
     email         text                     NOT NULL,
     password_hash text                     NOT NULL,
-    created_at    timestamp WITH TIME ZONE NOT NULL
+    created_at    timestamp WITH TIME ZONE NOT NULL,
+    email_verified boolean                 NOT NULL DEFAULT false,
+    role          user_role                NOT NULL DEFAULT 'student'
 );
 
+create unique index users_email_lower_key
+    on users (lower(email));
+
 create table user_sessions
 (
     ssid       text                     NOT NULL
         PRIMARY KEY,
     expires_at timestamp WITH TIME ZONE NOT NULL,
-    belongs_to uuid NOT NULL
-);
\ No newline at end of file
+    belongs_to uuid NOT NULL,
+    ip         text,
+    user_agent text
+);
+
+create table external_identities
+(
+    issuer     text                     NOT NULL,
+    subject    text                     NOT NULL,
+    belongs_to uuid                     NOT NULL,
+    linked_at  timestamp WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (issuer, subject)
+);
+
+create table refresh_tokens
+(
+    token      text                     NOT NULL
+        PRIMARY KEY,
+    belongs_to uuid                     NOT NULL,
+    expires_at timestamp WITH TIME ZONE NOT NULL
+);
+
+create table password_reset_tokens
+(
+    token      text                     NOT NULL
+        PRIMARY KEY,
+    belongs_to uuid                     NOT NULL,
+    expires_at timestamp WITH TIME ZONE NOT NULL
+);
+
+create table user_totp
+(
+    belongs_to     uuid                     NOT NULL
+        PRIMARY KEY,
+    secret         text                     NOT NULL,
+    confirmed      boolean                  NOT NULL DEFAULT false,
+    last_used_step bigint,
+    created_at     timestamp WITH TIME ZONE NOT NULL
+);
+
+create table totp_recovery_codes
+(
+    belongs_to uuid NOT NULL,
+    code_hash  text NOT NULL,
+    PRIMARY KEY (belongs_to, code_hash)
+);
+
+create table login_failures
+(
+    belongs_to   uuid    NOT NULL,
+    failures     integer NOT NULL,
+    locked_until timestamp WITH TIME ZONE,
+    client       text    NOT NULL,
+    PRIMARY KEY (belongs_to, client)
+);
+
+create table teacher_profiles
+(
+    belongs_to uuid   NOT NULL
+        PRIMARY KEY,
+    subjects   text[] NOT NULL
+);
+
+create table parent_children
+(
+    parent_id uuid                     NOT NULL,
+    child_id  uuid                     NOT NULL,
+    linked_at timestamp WITH TIME ZONE NOT NULL,
+    PRIMARY KEY (parent_id, child_id)
+);
+
+create table parent_link_codes
+(
+    code       text                     NOT NULL
+        PRIMARY KEY,
+    child_id   uuid                     NOT NULL,
+    issued_by  uuid                     NOT NULL,
+    expires_at timestamp WITH TIME ZONE NOT NULL
+);
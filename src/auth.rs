@@ -1,6 +1,8 @@
-use axum::extract::Path;
-use axum::{Extension, Json};
-use chrono::{DateTime, Duration, Utc};
+use axum::extract::{FromRequest, RequestParts};
+use axum::http::header::{AUTHORIZATION, USER_AGENT};
+use axum::http::HeaderMap;
+use axum::{async_trait, Extension};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use pbkdf2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
 use pbkdf2::Pbkdf2;
 use rand::{thread_rng, Rng};
@@ -8,13 +10,31 @@ use rand_core::OsRng;
 use serde::{Deserialize, Serialize, Serializer};
 use serde_with::skip_serializing_none;
 use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::net::IpAddr;
 use std::ops::Add;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
 
-use crate::models::{StudentData, StudentSession};
+use crate::config::{AuthMode, Config, SessionCeilingPolicy, SessionMode};
+use crate::db::{self, with_retry};
+use crate::err::{BoundedPath, JsonBody};
+use crate::lockout::{check_lockout, clear_login_failures, record_login_failure};
+use crate::mail::Mailer;
+use crate::models::{Role, StudentSession, UserData, USER_COLUMNS};
+use crate::ratelimit::SessionBudget;
+use crate::security::client_ip;
+use crate::tokens::{mint_access_token, verify_access_token};
+use crate::totp::check_second_factor;
+use crate::verify::{send_verification_email, within_verification_grace};
 use crate::{breaks, proceeds, Error, Payload};
-use sqlx::PgPool;
+use sqlx::{Connection, PgConnection, PgExecutor, PgPool};
 use uuid::Uuid;
 
+const USERNAME_MIN_LEN: usize = 3;
+const USERNAME_MAX_LEN: usize = 32;
+const SSID_INSERT_ATTEMPTS: usize = 3;
+
 #[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq)]
 pub enum AuthResult {
     Success,
@@ -32,85 +52,313 @@ impl Serialize for AuthResult {
 }
 
 pub async fn drop_session(
-    Json(EnsureSession { ssid, value }): Json<EnsureSession<DropSession>>,
+    client: ClientInfo,
+    JsonBody(EnsureSession { ssid, value }): JsonBody<EnsureSession<DropSession>>,
     Extension(pg): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(budget): Extension<SessionBudget>,
 ) -> Payload<SessionBasedResponse<SessionDropped>> {
-    let auth_result = ensure_authenticated(Some(ssid.clone()), &pg).await?;
-    if auth_result != AuthResult::Success {
+    if let Err(err) = validate_student_id(&value.uuid, &config) {
+        return breaks(err);
+    }
+
+    let session =
+        match authenticated_session(Some(ssid.clone()), &client, &pg, &config, &budget).await? {
+            Some(session) => session,
+            None => {
+                return proceeds(SessionBasedResponse {
+                    auth_result: AuthResult::InvalidSession,
+                    value: Some(SessionDropped::new(value.uuid, DropOutcome::NotFound)),
+                })
+            }
+        };
+    if session.belongs_to != value.uuid {
         return proceeds(SessionBasedResponse {
-            auth_result,
-            value: None,
+            auth_result: AuthResult::Success,
+            value: Some(SessionDropped::new(value.uuid, DropOutcome::NotOwned)),
         });
     }
 
-    let affected = sqlx::query("DELETE FROM user_sessions WHERE ssid = $1 AND belongs_to = $2")
-        .bind(&ssid)
-        .bind(&value.uuid)
-        .execute(&pg)
-        .await
-        .map_err(Error::from)?;
+    let affected = if config.auth_mode == AuthMode::Jwt {
+        // access tokens cannot be revoked, but they can no longer be renewed
+        sqlx::query("DELETE FROM refresh_tokens WHERE belongs_to = $1")
+            .bind(value.uuid)
+            .execute(&pg)
+            .await
+    } else {
+        sqlx::query("DELETE FROM user_sessions WHERE ssid = $1 AND belongs_to = $2")
+            .bind(&ssid)
+            .bind(value.uuid)
+            .execute(&pg)
+            .await
+    }
+    .map_err(Error::from)?;
+
+    // the session may have been dropped concurrently since we looked it up
+    let outcome = if affected.rows_affected() >= 1 {
+        DropOutcome::Dropped
+    } else {
+        DropOutcome::NotFound
+    };
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(SessionDropped::new(value.uuid, outcome)),
+    })
+}
+
+/// Reports the server clock alongside the session expiry, so clients can work out the
+/// remaining session lifetime without trusting their own clock.
+pub async fn sync_session(
+    client: ClientInfo,
+    JsonBody(EnsureSession { ssid, .. }): JsonBody<EnsureSession<SyncSession>>,
+    Extension(pg): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(budget): Extension<SessionBudget>,
+) -> Payload<SessionBasedResponse<SessionClock>> {
+    session_clock(Some(ssid), &client, &pg, &config, &budget).await
+}
+
+/// Same as [`sync_session`] for `GET` requests, which carry the session id in an
+/// `Authorization: Bearer` header instead of a body.
+pub async fn sync_session_with_header(
+    client: ClientInfo,
+    headers: HeaderMap,
+    Extension(pg): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(budget): Extension<SessionBudget>,
+) -> Payload<SessionBasedResponse<SessionClock>> {
+    let ssid = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|ssid| ssid.trim().to_string());
+    session_clock(ssid, &client, &pg, &config, &budget).await
+}
 
-    return proceeds(SessionBasedResponse {
-        auth_result,
-        value: Some(SessionDropped {
-            student_id: value.uuid,
-            drop_success: affected.rows_affected() >= 1,
-        }),
-    });
+async fn session_clock(
+    ssid: Option<String>,
+    client: &ClientInfo,
+    pg: &PgPool,
+    config: &Config,
+    budget: &SessionBudget,
+) -> Payload<SessionBasedResponse<SessionClock>> {
+    let session = authenticated_session(ssid, client, pg, config, budget).await?;
+    proceeds(match session {
+        Some(session) => SessionBasedResponse {
+            auth_result: AuthResult::Success,
+            value: Some(SessionClock {
+                server_time: Utc::now(),
+                expires_at: session.expires_at,
+            }),
+        },
+        None => SessionBasedResponse {
+            auth_result: AuthResult::InvalidSession,
+            value: None,
+        },
+    })
+}
+
+/// Rejects ids that can never belong to a user before they reach the database:
+/// the nil UUID always, and anything but a v4 UUID when `STRICT_UUID_V4` is set.
+pub fn validate_student_id(uuid: &Uuid, config: &Config) -> Result<(), Error> {
+    if uuid.is_nil() {
+        return Err(Error::InvalidPayload {
+            message: "`uuid` must not be the nil UUID".to_string(),
+        });
+    }
+    if config.strict_uuid_v4 && uuid.get_version_num() != 4 {
+        return Err(Error::InvalidPayload {
+            message: format!("`uuid` must be a version 4 UUID, got `{}`", uuid),
+        });
+    }
+    Ok(())
 }
 
 pub async fn ensure_authenticated(
     session_id: Option<String>,
+    client: &ClientInfo,
     pg: &PgPool,
+    config: &Config,
+    budget: &SessionBudget,
 ) -> anyhow::Result<AuthResult, Error> {
-    return if let None = session_id {
-        Ok(AuthResult::InvalidSession)
-    } else if let Some(ssid) = session_id {
-        if ssid.is_empty() {
-            return Ok(AuthResult::InvalidSession);
+    Ok(
+        match authenticated_session(session_id, client, pg, config, budget).await? {
+            Some(_) => AuthResult::Success,
+            None => AuthResult::InvalidSession,
+        },
+    )
+}
+
+/// Looks up a live session of an existing user by id, deleting it instead if it has already expired.
+/// With `AUTH_MODE=jwt` the id is an access token instead, and only its signature and expiry are checked:
+/// it authenticates until it expires even if its user is gone, and `SESSION_BIND` does not apply to it.
+/// Sessions used from a client that does not match their `SESSION_BIND` binding are not returned.
+/// Every session that is returned spends from its `SESSION_RATE_PER_MINUTE` budget, failing once it
+/// is exhausted. Access tokens share the budget of their student, as refreshing yields a new token.
+pub async fn authenticated_session(
+    session_id: Option<String>,
+    client: &ClientInfo,
+    pg: &PgPool,
+    config: &Config,
+    budget: &SessionBudget,
+) -> Result<Option<StudentSession>, Error> {
+    let ssid = match session_id {
+        Some(ssid) if !ssid.is_empty() => ssid,
+        _ => return Ok(None),
+    };
+    if config.auth_mode == AuthMode::Jwt {
+        // access tokens are verified without touching the database
+        let session = verify_access_token(&ssid, config).ok().and_then(|claims| {
+            Some(StudentSession {
+                belongs_to: claims.sub,
+                expires_at: Utc.timestamp_opt(claims.exp, 0).single()?,
+                ssid,
+                ip: None,
+                user_agent: None,
+            })
+        });
+        if let Some(session) = &session {
+            budget.spend(&format!("student:{}", session.belongs_to))?;
         }
-        let session = sqlx::query_as::<_, StudentSession>(
-            "SELECT * FROM user_sessions WHERE ssid = $1 LIMIT 1",
-        )
-        .bind(&ssid)
-        .fetch_optional(pg)
-        .await
-        .map_err(Error::from)?;
+        return Ok(session);
+    }
+    let mut conn = db::acquire(pg, config).await.map_err(Error::from)?;
+    // sessions of users that no longer exist never authenticate
+    let session = sqlx::query_as::<_, StudentSession>(
+        "SELECT s.* FROM user_sessions s JOIN users u ON u.uuid = s.belongs_to \
+        WHERE s.ssid = $1 LIMIT 1",
+    )
+    .bind(&ssid)
+    .fetch_optional(&mut conn)
+    .await
+    .map_err(Error::from)?;
 
-        if let Some(session) = session {
-            let expires_at = session.expires_at;
-            if Utc::now().gt(&expires_at) {
-                sqlx::query("DELETE FROM user_sessions WHERE ssid = $1")
-                    .bind(&ssid)
-                    .execute(pg)
-                    .await
-                    .map_err(Error::from)?;
-                return Ok(AuthResult::InvalidSession);
-            }
-            Ok(AuthResult::Success)
-        } else {
-            Ok(AuthResult::InvalidSession)
+    if let Some(session) = session {
+        let expires_at = session.expires_at;
+        if Utc::now().gt(&expires_at) {
+            sqlx::query("DELETE FROM user_sessions WHERE ssid = $1")
+                .bind(&ssid)
+                .execute(&mut conn)
+                .await
+                .map_err(Error::from)?;
+            return Ok(None);
         }
+        if !client.matches_session(&session, config) {
+            log::info!(
+                "Rejected session of {} used from a client it is not bound to",
+                session.belongs_to
+            );
+            return Ok(None);
+        }
+        budget.spend(&format!("session:{}", session.ssid))?;
+        Ok(Some(session))
     } else {
-        Ok(AuthResult::InvalidSession)
-    };
+        Ok(None)
+    }
+}
+
+/// Where a request came from, as far as session binding is concerned.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    /// Checks the parts of the client selected by `SESSION_BIND` against the ones the
+    /// session was created with. Values the session never recorded are not enforced.
+    pub fn matches_session(&self, session: &StudentSession, config: &Config) -> bool {
+        let bind = config.session_bind;
+        let ip_ok = !bind.ip()
+            || match (&session.ip, self.ip) {
+                (Some(stored), Some(current)) => stored
+                    .parse::<IpAddr>()
+                    .map(|stored| same_network(stored, current, config))
+                    .unwrap_or(false),
+                (Some(_), None) => false,
+                (None, _) => true,
+            };
+        let ua_ok = !bind.user_agent()
+            || match &session.user_agent {
+                Some(stored) => self.user_agent.as_deref() == Some(stored.as_str()),
+                None => true,
+            };
+        ip_ok && ua_ok
+    }
+}
+
+/// Compares two addresses on the network prefix configured for session binding,
+/// so clients hopping between addresses of one network keep their session.
+fn same_network(a: IpAddr, b: IpAddr, config: &Config) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => {
+            let mask = prefix_mask(config.session_bind_ipv4_prefix.min(32), 32) as u32;
+            u32::from(a) & mask == u32::from(b) & mask
+        }
+        (IpAddr::V6(a), IpAddr::V6(b)) => {
+            let mask = prefix_mask(config.session_bind_ipv6_prefix.min(128), 128);
+            u128::from(a) & mask == u128::from(b) & mask
+        }
+        _ => false,
+    }
+}
+
+fn prefix_mask(prefix: u32, bits: u32) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        (u128::MAX << (128 - prefix)) >> (128 - bits)
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        Ok(ClientInfo {
+            ip: client_ip(req.extensions(), req.headers()),
+            user_agent: req
+                .headers()
+                .get(USER_AGENT)
+                .and_then(|ua| ua.to_str().ok())
+                .map(str::to_string),
+        })
+    }
 }
 
 pub async fn login_student(
-    Json(login): Json<LoginStudent>,
+    client: ClientInfo,
+    JsonBody(login): JsonBody<LoginStudent>,
     Extension(pg): Extension<PgPool>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Extension(config): Extension<Arc<Config>>,
 ) -> Payload<LoggedInStudent> {
     if login.password.is_empty() {
         return breaks(Error::InvalidPayload {
             message: "`password` parameter was empty".to_string(),
         });
     }
+    if let Err(err) = validate_student_id(&login.uuid, &config) {
+        return breaks(err);
+    }
 
-    let user = sqlx::query_as::<_, StudentData>("SELECT * FROM users WHERE uuid = $1 LIMIT 1")
-        .bind(&login.uuid)
-        .fetch_optional(&pg)
-        .await
-        .map_err(Error::from)?;
+    let user = sqlx::query_as::<_, UserData>(&format!(
+        "SELECT {} FROM users WHERE uuid = $1 LIMIT 1",
+        USER_COLUMNS
+    ))
+    .bind(login.uuid)
+    .fetch_optional(&pg)
+    .await
+    .map_err(Error::from)?;
+
+    // verify even for unknown users, so both failure paths take the same time
+    let matches = verify_password_padded(
+        &login.password,
+        user.as_ref().map(|user| user.password_hash.as_str()),
+        &config,
+    )
+    .await?;
 
     let student = if let Some(user) = user {
         user
@@ -119,81 +367,396 @@ pub async fn login_student(
             message: format!("User with uuid `{}` does not exist!", login.uuid),
         });
     };
-    let hash = PasswordHash::new(&student.password_hash).map_err(Error::from)?;
-    let matches = Pbkdf2
-        .verify_password(login.password.as_bytes(), &hash)
-        .is_ok();
+    if let Err(err) = check_lockout(student.uuid, &client, &pg).await {
+        return breaks(err);
+    }
     if !matches {
+        record_login_failure(student.uuid, &client, &pg, &config).await?;
         return breaks(Error::AuthenticationFailure {
             message: "Passwords do not match!".to_string(),
         });
     }
+    if let Err(err) = admit_student(
+        &student,
+        login.totp_code.as_deref(),
+        &client,
+        &pg,
+        mailer.as_ref(),
+        &config,
+    )
+    .await
+    {
+        return breaks(err);
+    }
+
+    proceeds(start_session(student.uuid, &client, &pg, &config).await?)
+}
+
+/// The checks every login goes through once the student proved who they are, be it by
+/// password or single sign-on: email verification and the second factor. Callers check
+/// the lockout beforehand.
+pub async fn admit_student(
+    student: &UserData,
+    totp_code: Option<&str>,
+    client: &ClientInfo,
+    pg: &PgPool,
+    mailer: &dyn Mailer,
+    config: &Config,
+) -> Result<(), Error> {
+    if !within_verification_grace(student, config) {
+        // the earlier link may be lost or expired, so hand out a fresh one
+        send_verification_email(student, mailer, config).await;
+        return Err(Error::EmailNotVerified {
+            message: "Verify your email to log in, a new verification link was sent".to_string(),
+        });
+    }
+    if let Err(err) = check_second_factor(student.uuid, totp_code, pg).await {
+        if matches!(err, Error::AuthenticationFailure { .. }) {
+            record_login_failure(student.uuid, client, pg, config).await?;
+        }
+        return Err(err);
+    }
+    clear_login_failures(student.uuid, client, pg).await?;
+    Ok(())
+}
 
-    let existing_session = sqlx::query_as::<_, StudentSession>(
-        "SELECT * FROM user_sessions WHERE belongs_to = $1 LIMIT 1",
+/// Hands out a session for an already authenticated student. In single-session mode
+/// their existing session is reused when it is bound to the same client.
+pub async fn start_session(
+    student_id: Uuid,
+    client: &ClientInfo,
+    pg: &PgPool,
+    config: &Config,
+) -> Result<LoggedInStudent, Error> {
+    if config.auth_mode == AuthMode::Jwt {
+        return issue_tokens(student_id, pg, config).await;
+    }
+
+    // cleaning up, evicting and inserting either all happen or none does, and are
+    // retried together on transient failures
+    with_retry(config.db_max_retries, || {
+        start_session_once(student_id, client, pg, config)
+    })
+    .await
+    .map_err(Error::from)?
+}
+
+/// One attempt at [`start_session`] in a transaction of its own. Database errors are
+/// returned on the outside, to be retried; refused logins on the inside.
+async fn start_session_once(
+    student_id: Uuid,
+    client: &ClientInfo,
+    pg: &PgPool,
+    config: &Config,
+) -> Result<Result<LoggedInStudent, Error>, sqlx::Error> {
+    let mut conn = db::acquire(pg, config).await?;
+    let mut tx = conn.begin().await?;
+    if config.session_mode == SessionMode::Single {
+        if let Some(existing) = single_session(student_id, client, &mut tx, config).await? {
+            tx.commit().await?;
+            // already authenticated
+            return Ok(Ok(LoggedInStudent {
+                session_id: existing.ssid,
+                student_id: existing.belongs_to,
+                expires_at: existing.expires_at,
+                refresh_token: None,
+            }));
+        }
+    }
+
+    if !enforce_session_ceiling(&mut tx, config).await? {
+        return Ok(Err(Error::ServiceUnavailable {
+            message: "Too many active sessions, try again later".to_string(),
+        }));
+    }
+
+    let expires_in = Duration::days(2);
+    let expires_at = Utc::now().add(expires_in);
+    let ssid = match insert_session(&mut tx, student_id, expires_at, client, generate_ssid).await? {
+        Some(ssid) => ssid,
+        None => {
+            return Ok(Err(Error::InternalError {
+                kind: "DatabaseError",
+                message: "Could not update session ids!".to_string(),
+            }))
+        }
+    };
+    tx.commit().await?;
+
+    Ok(Ok(LoggedInStudent {
+        session_id: ssid,
+        student_id,
+        expires_at,
+        refresh_token: None,
+    }))
+}
+
+/// Mints an access token with a refresh token stored for renewing it.
+async fn issue_tokens(
+    student_id: Uuid,
+    pg: &PgPool,
+    config: &Config,
+) -> Result<LoggedInStudent, Error> {
+    let (access_token, expires_at) = mint_access_token(student_id, config)?;
+    let refresh_token = generate_ssid();
+    sqlx::query("INSERT INTO refresh_tokens (token, belongs_to, expires_at) VALUES ($1, $2, $3)")
+        .bind(&refresh_token)
+        .bind(student_id)
+        .bind(Utc::now() + Duration::days(config.refresh_token_ttl_days))
+        .execute(pg)
+        .await
+        .map_err(Error::from)?;
+
+    Ok(LoggedInStudent {
+        session_id: access_token,
+        student_id,
+        expires_at,
+        refresh_token: Some(refresh_token),
+    })
+}
+
+/// Trades a refresh token for a new access and refresh token pair. Each refresh token
+/// works only once.
+pub async fn refresh_student_tokens(
+    JsonBody(RefreshTokens { refresh_token }): JsonBody<RefreshTokens>,
+    Extension(pg): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Payload<LoggedInStudent> {
+    if config.auth_mode != AuthMode::Jwt {
+        return breaks(Error::InvalidPayload {
+            message: "Refresh tokens are only issued when `AUTH_MODE=jwt`".to_string(),
+        });
+    }
+
+    let consumed = sqlx::query_as::<_, (Uuid, DateTime<Utc>)>(
+        "DELETE FROM refresh_tokens WHERE token = $1 RETURNING belongs_to, expires_at",
     )
-    .bind(login.uuid)
+    .bind(&refresh_token)
     .fetch_optional(&pg)
     .await
     .map_err(Error::from)?;
+    let student_id = match consumed {
+        Some((student_id, expires_at)) if expires_at > Utc::now() => student_id,
+        _ => {
+            return breaks(Error::AuthenticationFailure {
+                message: "Refresh token is invalid or expired".to_string(),
+            })
+        }
+    };
 
-    if let Some(existing) = existing_session {
-        // already authenticated
-        return proceeds(LoggedInStudent {
-            session_id: existing.ssid,
-            student_id: existing.belongs_to,
-            expires_at: existing.expires_at,
+    let exists =
+        sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM users WHERE uuid = $1)")
+            .bind(student_id)
+            .fetch_one(&pg)
+            .await
+            .map_err(Error::from)?;
+    if !exists {
+        return breaks(Error::UserDoesNotExist {
+            message: format!("User with uuid `{}` does not exist!", student_id),
         });
     }
 
+    proceeds(issue_tokens(student_id, &pg, &config).await?)
+}
+
+pub fn generate_ssid() -> String {
     let ssid_bytes: [u8; 32] = thread_rng().gen();
 
     let mut hasher: Sha256 = Digest::new();
-    hasher.update(&ssid_bytes);
+    hasher.update(ssid_bytes);
     let result = hasher.finalize();
-    let ssid = hex::encode(result);
+    hex::encode(result)
+}
 
-    let expires_in = Duration::days(2);
-    let expires_at = Utc::now().add(expires_in);
-    let res = sqlx::query("INSERT INTO user_sessions VALUES($1, $2, $3)")
+/// Stores a new session with an id drawn from `next_ssid`, drawing a fresh id
+/// if the previous one collided with an existing session. Each attempt runs in a
+/// savepoint, so a collision does not abort a surrounding transaction. Gives up with
+/// `None` after `SSID_INSERT_ATTEMPTS` collisions.
+pub async fn insert_session<G>(
+    conn: &mut PgConnection,
+    belongs_to: Uuid,
+    expires_at: DateTime<Utc>,
+    client: &ClientInfo,
+    mut next_ssid: G,
+) -> Result<Option<String>, sqlx::Error>
+where
+    G: FnMut() -> String,
+{
+    for _ in 0..SSID_INSERT_ATTEMPTS {
+        let ssid = next_ssid();
+        let mut attempt = conn.begin().await?;
+        let res = sqlx::query(
+            "INSERT INTO user_sessions (ssid, expires_at, belongs_to, ip, user_agent) \
+            VALUES ($1, $2, $3, $4, $5)",
+        )
         .bind(&ssid)
-        .bind(&expires_at)
-        .bind(&student.uuid)
-        .execute(&pg)
-        .await
-        .map_err(Error::from)?;
+        .bind(expires_at)
+        .bind(belongs_to)
+        .bind(client.ip.map(|ip| ip.to_string()))
+        .bind(&client.user_agent)
+        .execute(&mut attempt)
+        .await;
 
-    if res.rows_affected() < 1 {
-        return breaks(Error::InternalError {
-            kind: "DatabaseError",
-            message: "Could not update session ids!".to_string(),
-        });
+        match res {
+            Ok(res) if res.rows_affected() >= 1 => {
+                attempt.commit().await?;
+                return Ok(Some(ssid));
+            }
+            Ok(_) => break,
+            Err(err) if db::is_unique_violation(&err) => {
+                attempt.rollback().await?;
+                log::warn!("Generated session id collided with an existing one, regenerating");
+            }
+            Err(err) => return Err(err),
+        }
     }
 
-    return proceeds(LoggedInStudent {
-        session_id: ssid,
-        student_id: student.uuid,
-        expires_at,
-    });
+    Ok(None)
+}
+
+/// Verifies `password` against `hash`, or against a throwaway hash when there is no user,
+/// and pads the whole call to at least `PASSWORD_VERIFY_MIN_MS`.
+async fn verify_password_padded(
+    password: &str,
+    hash: Option<&str>,
+    config: &Config,
+) -> Result<bool, Error> {
+    let started = Instant::now();
+    let matches = match hash {
+        Some(hash) => {
+            let hash = PasswordHash::new(hash)?;
+            Pbkdf2.verify_password(password.as_bytes(), &hash).is_ok()
+        }
+        None => {
+            let hash = PasswordHash::new(dummy_password_hash())?;
+            let _ = Pbkdf2.verify_password(password.as_bytes(), &hash);
+            false
+        }
+    };
+
+    let floor = std::time::Duration::from_millis(config.password_verify_min_ms);
+    if let Some(remaining) = floor.checked_sub(started.elapsed()) {
+        tokio::time::sleep(remaining).await;
+    }
+    Ok(matches)
+}
+
+fn dummy_password_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| {
+        Pbkdf2
+            .hash_password(b"opendiary-dummy", &SaltString::generate(&mut OsRng))
+            .expect("hashing a constant password cannot fail")
+            .to_string()
+    })
+}
+
+/// Finds the one session a student may keep in single-session mode, deleting every other
+/// one: expired sessions, sessions bound to another client, and extras left over from
+/// running in multi-session mode.
+async fn single_session(
+    student_id: Uuid,
+    client: &ClientInfo,
+    conn: &mut PgConnection,
+    config: &Config,
+) -> Result<Option<StudentSession>, sqlx::Error> {
+    let sessions = sqlx::query_as::<_, StudentSession>(
+        "SELECT * FROM user_sessions WHERE belongs_to = $1 ORDER BY expires_at DESC",
+    )
+    .bind(student_id)
+    .fetch_all(&mut *conn)
+    .await?;
+    if sessions.len() > 1 {
+        log::warn!(
+            "Student {} has {} sessions in single-session mode, dropping extras",
+            student_id,
+            sessions.len()
+        );
+    }
+
+    let now = Utc::now();
+    let mut kept = None;
+    let mut stale = Vec::new();
+    for session in sessions {
+        if kept.is_none() && session.expires_at > now && client.matches_session(&session, config) {
+            kept = Some(session);
+        } else {
+            stale.push(session.ssid);
+        }
+    }
+    if !stale.is_empty() {
+        sqlx::query("DELETE FROM user_sessions WHERE ssid = ANY($1)")
+            .bind(&stale)
+            .execute(conn)
+            .await?;
+    }
+    Ok(kept)
+}
+
+/// Makes room for one more session under `MAX_TOTAL_SESSIONS`, either by evicting
+/// the sessions closest to expiry or by refusing the login, depending on the policy.
+/// Returns whether there is room.
+async fn enforce_session_ceiling(
+    conn: &mut PgConnection,
+    config: &Config,
+) -> Result<bool, sqlx::Error> {
+    if config.max_total_sessions <= 0 {
+        return Ok(true);
+    }
+
+    let now = Utc::now();
+    let (live,): (i64,) =
+        sqlx::query_as("SELECT COUNT(*) FROM user_sessions WHERE expires_at > $1")
+            .bind(now)
+            .fetch_one(&mut *conn)
+            .await?;
+    if live < config.max_total_sessions {
+        return Ok(true);
+    }
+
+    match config.session_ceiling_policy {
+        SessionCeilingPolicy::Reject => Ok(false),
+        SessionCeilingPolicy::Evict => {
+            let evicted = sqlx::query(
+                "DELETE FROM user_sessions WHERE ssid IN \
+                (SELECT ssid FROM user_sessions WHERE expires_at > $1 \
+                ORDER BY expires_at ASC LIMIT $2)",
+            )
+            .bind(now)
+            .bind(live - config.max_total_sessions + 1)
+            .execute(conn)
+            .await?;
+            log::info!(
+                "Session ceiling reached, evicted {} session(s)",
+                evicted.rows_affected()
+            );
+            Ok(true)
+        }
+    }
 }
 
 pub async fn query_user_id(
-    Path(username): Path<String>,
+    BoundedPath(username): BoundedPath<String>,
     Extension(pg): Extension<PgPool>,
 ) -> Payload<CreatedStudent> {
+    let username = normalize_username(&username);
     if username.is_empty() {
         return breaks(Error::InvalidPayload {
             message: "`username` parameter was empty".to_string(),
         });
     }
 
-    let user = sqlx::query_as::<_, StudentData>("SELECT * FROM users WHERE username = $1 LIMIT 1")
-        .bind(&username)
-        .fetch_optional(&pg)
-        .await
-        .map_err(Error::from)?;
+    // usernames registered before normalization may still contain upper case letters
+    let user = sqlx::query_as::<_, UserData>(&format!(
+        "SELECT {} FROM users WHERE lower(username) = $1 LIMIT 1",
+        USER_COLUMNS
+    ))
+    .bind(&username)
+    .fetch_optional(&pg)
+    .await
+    .map_err(Error::from)?;
 
-    return if let Some(user) = user {
+    if let Some(user) = user {
         proceeds(CreatedStudent {
             student_id: user.uuid,
         })
@@ -201,83 +764,232 @@ pub async fn query_user_id(
         breaks(Error::UserDoesNotExist {
             message: format!("User with name `{}` does not exist!", username),
         })
+    }
+}
+
+pub async fn normalize_student_username(
+    JsonBody(NormalizeUsername { username }): JsonBody<NormalizeUsername>,
+) -> Payload<NormalizedUsername> {
+    let normalized = normalize_username(&username);
+    proceeds(NormalizedUsername {
+        valid: is_valid_username(&normalized),
+        normalized,
+    })
+}
+
+pub fn normalize_username(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+/// Letters and digits of any script are allowed, so Cyrillic names keep working, but
+/// whitespace, control characters and other punctuation are not.
+pub fn is_valid_username(username: &str) -> bool {
+    (USERNAME_MIN_LEN..=USERNAME_MAX_LEN).contains(&username.chars().count())
+        && username
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+/// Checks the domain of `email` against `ALLOWED_EMAIL_DOMAINS`, where `*.example.org`
+/// matches any subdomain of `example.org`. An empty allowlist allows every domain.
+pub fn email_domain_allowed(email: &str, allowed: &[String]) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+    let domain = match email.rsplit_once('@') {
+        Some((_, domain)) => domain.trim().to_lowercase(),
+        None => return false,
     };
+    allowed
+        .iter()
+        .any(|pattern| match pattern.strip_prefix("*.") {
+            Some(parent) => domain
+                .strip_suffix(parent)
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => &domain == pattern,
+        })
+}
+
+/// Rejects empty passwords and ones found in `PASSWORD_BLOCKLIST_PATH`.
+pub fn check_new_password(password: &str, config: &Config) -> Result<(), Error> {
+    if password.is_empty() {
+        return Err(Error::MissingCredentials {
+            message: "Provided password was empty!".to_string(),
+        });
+    }
+    if config.password_blocklist.contains(&password.to_lowercase()) {
+        return Err(Error::WeakPassword {
+            message: "Provided password is too common, choose another one".to_string(),
+        });
+    }
+    Ok(())
+}
+
+pub fn hash_password(password: &str) -> Result<String, Error> {
+    Ok(Pbkdf2
+        .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))?
+        .to_string())
 }
 
 pub async fn register_student(
-    Json(student): Json<CreateStudent>,
+    JsonBody(student): JsonBody<CreateStudent>,
     Extension(pg): Extension<PgPool>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Extension(config): Extension<Arc<Config>>,
 ) -> Payload<CreatedStudent> {
-    if student.password.is_empty() {
-        return breaks(Error::MissingCredentials {
-            message: "Provided password was empty!".to_string(),
+    proceeds(CreatedStudent {
+        student_id: create_account(student, Role::Student, &pg, mailer.as_ref(), &config).await?,
+    })
+}
+
+/// Validates and stores a new account with the given role, emailing it a verification link.
+pub async fn create_account(
+    student: CreateStudent,
+    role: Role,
+    pg: &PgPool,
+    mailer: &dyn Mailer,
+    config: &Config,
+) -> Result<Uuid, Error> {
+    let user = new_account(student, role, pg, config).await?;
+    with_retry(config.db_max_retries, || insert_account(&user, pg))
+        .await
+        .map_err(account_not_stored)?;
+
+    send_verification_email(&user, mailer, config).await;
+    Ok(user.uuid)
+}
+
+/// Validates a new account with the given role, ready to be stored with [`insert_account`].
+pub async fn new_account(
+    mut student: CreateStudent,
+    role: Role,
+    pg: &PgPool,
+    config: &Config,
+) -> Result<UserData, Error> {
+    student.username = normalize_username(&student.username);
+    if !is_valid_username(&student.username) {
+        return Err(Error::InvalidPayload {
+            message: format!(
+                "Username must be {}-{} characters of letters, digits, `_`, `.` or `-`",
+                USERNAME_MIN_LEN, USERNAME_MAX_LEN
+            ),
         });
     }
 
-    let user = sqlx::query_as::<_, StudentData>(
-        "SELECT * FROM users WHERE username = $2 OR email = $1 LIMIT 1",
-    )
+    check_new_password(&student.password, config)?;
+
+    if !email_domain_allowed(&student.email, &config.allowed_email_domains) {
+        return Err(Error::EmailDomainNotAllowed {
+            message: "Registration is not open for this email domain".to_string(),
+        });
+    }
+
+    let user = sqlx::query_as::<_, UserData>(&format!(
+        "SELECT {} FROM users WHERE lower(username) = $2 OR lower(email) = lower($1) LIMIT 1",
+        USER_COLUMNS
+    ))
     .bind(&student.email)
     .bind(&student.username)
-    .fetch_optional(&pg)
+    .fetch_optional(pg)
     .await
     .map_err(Error::from)?;
-    if let Some(_) = user {
-        return breaks(Error::UserAlreadyExists {
+    if user.is_some() {
+        return Err(Error::UserAlreadyExists {
             message: "User with provided email/username already exists!".to_string(),
         });
     }
 
-    let user = StudentData {
+    Ok(UserData {
         uuid: Uuid::new_v4(),
         username: student.username,
         name: student.name,
         surname: student.surname,
         patronymic: student.patronymic,
         email: student.email,
-        password_hash: Pbkdf2
-            .hash_password(
-                student.password.as_bytes(),
-                &SaltString::generate(&mut OsRng),
-            )?
-            .to_string(),
+        password_hash: hash_password(&student.password)?,
         created_at: Utc::now(),
-    };
+        email_verified: false,
+        role,
+    })
+}
 
-    let res = sqlx::query("INSERT INTO users VALUES ($1, $2, $3, $4, $5, $6, $7, $8)")
-        .bind(user.uuid.clone())
-        .bind(user.username)
-        .bind(user.name)
-        .bind(user.surname)
-        .bind(user.patronymic)
-        .bind(user.email)
-        .bind(user.password_hash)
-        .bind(user.created_at)
-        .execute(&pg)
-        .await
-        .map_err(|err| Error::InternalError {
-            kind: "DatabaseError",
-            message: format!("{:?}", err),
-        })?;
+/// Stores an account from [`new_account`], in a transaction when `executor` is one.
+pub async fn insert_account<'c, E: PgExecutor<'c>>(
+    user: &UserData,
+    executor: E,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "INSERT INTO users ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+        USER_COLUMNS
+    ))
+    .bind(user.uuid)
+    .bind(&user.username)
+    .bind(&user.name)
+    .bind(&user.surname)
+    .bind(&user.patronymic)
+    .bind(&user.email)
+    .bind(&user.password_hash)
+    .bind(user.created_at)
+    .bind(user.email_verified)
+    .bind(user.role)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
 
-    if res.rows_affected() < 1 {
-        return breaks(Error::InternalError {
-            kind: "DatabaseError",
-            message: "Could not save data to database!".to_string(),
-        });
+/// The error for an [`insert_account`] that failed.
+pub fn account_not_stored(err: sqlx::Error) -> Error {
+    if db::is_unique_violation(&err) {
+        // lost a race against a concurrent registration with the same username/email
+        Error::UserAlreadyExists {
+            message: "User with provided email/username already exists!".to_string(),
+        }
     } else {
-        proceeds(CreatedStudent {
-            student_id: user.uuid,
-        })
+        Error::InternalError {
+            kind: "DatabaseError",
+            message: format!("{:?}", err),
+        }
     }
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct NormalizeUsername {
+    pub username: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NormalizedUsername {
+    pub normalized: String,
+    pub valid: bool,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+pub enum DropOutcome {
+    /// The session existed, belonged to the student and is now gone.
+    Dropped,
+    /// The session exists but belongs to someone else, so it was left alone.
+    NotOwned,
+    /// There was no live session with that id.
+    NotFound,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct SessionDropped {
     pub student_id: Uuid,
+    pub outcome: DropOutcome,
     pub drop_success: bool,
 }
 
+impl SessionDropped {
+    pub fn new(student_id: Uuid, outcome: DropOutcome) -> Self {
+        Self {
+            student_id,
+            outcome,
+            drop_success: outcome == DropOutcome::Dropped,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct DropSession {
     pub uuid: Uuid,
@@ -305,11 +1017,27 @@ pub struct SessionAlreadyExists {
     session: LoggedInStudent,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct SyncSession {}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionClock {
+    pub server_time: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[skip_serializing_none]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LoggedInStudent {
     session_id: String,
     student_id: Uuid,
     expires_at: DateTime<Utc>,
+    refresh_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RefreshTokens {
+    pub refresh_token: String,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -321,6 +1049,8 @@ pub struct CreatedStudent {
 pub struct LoginStudent {
     uuid: Uuid,
     password: String,
+    /// TOTP or recovery code, required once two-factor authentication is enabled
+    totp_code: Option<String>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -332,3 +1062,916 @@ pub struct CreateStudent {
     pub email: String,
     pub password: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    use axum::http::StatusCode;
+
+    #[test]
+    fn normalizes_and_validates_usernames() {
+        assert_eq!(normalize_username("  MixedCase_User  "), "mixedcase_user");
+        assert_eq!(normalize_username(" Иван.Петров "), "иван.петров");
+
+        assert!(is_valid_username("mixedcase_user"));
+        assert!(is_valid_username("иван.петров"));
+        assert!(!is_valid_username("ab"));
+        assert!(!is_valid_username(&"a".repeat(USERNAME_MAX_LEN + 1)));
+        assert!(!is_valid_username("two words"));
+        assert!(!is_valid_username("semi;colon"));
+    }
+
+    #[tokio::test]
+    async fn normalize_endpoint_returns_the_canonical_form() {
+        let response = testing::json(
+            normalize_student_username(JsonBody(NormalizeUsername {
+                username: "  John.DOE ".to_string(),
+            }))
+            .await,
+        );
+
+        assert_eq!(response["success"], true);
+        assert_eq!(response["normalized"], "john.doe");
+        assert_eq!(response["valid"], true);
+    }
+
+    #[tokio::test]
+    async fn finds_users_registered_with_mixed_case_names() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let uuid = testing::insert_user(&pool, "LegacyUser", "password").await;
+
+        let response = testing::json(
+            query_user_id(
+                BoundedPath(" legacyuser ".to_string()),
+                Extension(pool.clone()),
+            )
+            .await,
+        );
+
+        assert_eq!(response["success"], true);
+        assert_eq!(response["student_id"], uuid.to_string());
+    }
+
+    async fn session_exists(pool: &PgPool, ssid: &str) -> bool {
+        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM user_sessions WHERE ssid = $1)")
+            .bind(ssid)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_logins_at_the_session_ceiling() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let config = testing::config(&[
+            ("MAX_TOTAL_SESSIONS", "2"),
+            ("SESSION_CEILING_POLICY", "reject"),
+        ]);
+        for name in ["first", "second"] {
+            let user = testing::insert_user(&pool, name, "password").await;
+            testing::insert_session(&pool, user, Utc::now() + Duration::hours(1)).await;
+        }
+        let third = testing::insert_user(&pool, "third", "password").await;
+
+        let err = start_session(third, &ClientInfo::default(), &pool, &config)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::ServiceUnavailable { .. }));
+        assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn evicts_the_session_closest_to_expiry_at_the_ceiling() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let config = testing::config(&[
+            ("MAX_TOTAL_SESSIONS", "2"),
+            ("SESSION_CEILING_POLICY", "evict"),
+        ]);
+        let first = testing::insert_user(&pool, "first", "password").await;
+        let expiring = testing::insert_session(&pool, first, Utc::now() + Duration::hours(1)).await;
+        let second = testing::insert_user(&pool, "second", "password").await;
+        let lasting = testing::insert_session(&pool, second, Utc::now() + Duration::hours(9)).await;
+        let third = testing::insert_user(&pool, "third", "password").await;
+
+        let login = start_session(third, &ClientInfo::default(), &pool, &config)
+            .await
+            .unwrap();
+
+        assert!(!session_exists(&pool, &expiring).await);
+        assert!(session_exists(&pool, &lasting).await);
+        assert!(session_exists(&pool, &login.session_id).await);
+    }
+
+    #[test]
+    fn rejects_the_nil_uuid() {
+        let config = testing::config(&[]);
+
+        let err = validate_student_id(&Uuid::nil(), &config).unwrap_err();
+
+        assert!(matches!(err, Error::InvalidPayload { .. }));
+        assert!(validate_student_id(&Uuid::new_v4(), &config).is_ok());
+    }
+
+    #[test]
+    fn requires_v4_only_when_strict() {
+        // a version 1 UUID
+        let v1 = Uuid::parse_str("c232ab00-9414-11ec-b3c8-9f6bdeced846").unwrap();
+
+        assert!(validate_student_id(&v1, &testing::config(&[])).is_ok());
+        let strict = testing::config(&[("STRICT_UUID_V4", "true")]);
+        assert!(validate_student_id(&v1, &strict).is_err());
+        assert!(validate_student_id(&Uuid::new_v4(), &strict).is_ok());
+    }
+
+    #[tokio::test]
+    async fn login_rejects_the_nil_uuid_before_the_database() {
+        // the lazy pool points at a database that does not exist
+        let app = testing::app(testing::config(&[]), testing::lazy_pool()).await;
+
+        let request = testing::post(
+            "/session/login",
+            serde_json::json!({ "uuid": Uuid::nil(), "password": "password" }),
+        );
+        let body = testing::body_json(testing::send(&app, request).await).await;
+
+        assert_eq!(body["success"], false);
+        assert_eq!(body["error"], "InvalidPayload");
+    }
+
+    /// The fastest of a few runs, to keep scheduling noise out of timing comparisons.
+    async fn fastest_verify(
+        password: &str,
+        hash: Option<&str>,
+        config: &Config,
+    ) -> std::time::Duration {
+        let mut fastest = std::time::Duration::MAX;
+        for _ in 0..3 {
+            let started = Instant::now();
+            assert!(!verify_password_padded(password, hash, config)
+                .await
+                .unwrap());
+            fastest = fastest.min(started.elapsed());
+        }
+        fastest
+    }
+
+    #[tokio::test]
+    async fn verifies_a_dummy_hash_for_unknown_users() {
+        let config = testing::config(&[]);
+        let hash = hash_password("correct horse").unwrap();
+
+        let wrong_password = fastest_verify("wrong", Some(&hash), &config).await;
+        let unknown_user = fastest_verify("wrong", None, &config).await;
+
+        // without a dummy verification the unknown user path would return immediately
+        assert!(unknown_user * 2 > wrong_password);
+        assert!(wrong_password * 2 > unknown_user);
+    }
+
+    #[tokio::test]
+    async fn pads_verification_to_the_configured_floor() {
+        let config = testing::config(&[("PASSWORD_VERIFY_MIN_MS", "300")]);
+        let hash = hash_password("correct horse").unwrap();
+
+        let floor = std::time::Duration::from_millis(300);
+        assert!(fastest_verify("wrong", Some(&hash), &config).await >= floor);
+        assert!(fastest_verify("wrong", None, &config).await >= floor);
+    }
+
+    fn new_student(username: &str, email: &str) -> CreateStudent {
+        CreateStudent {
+            username: username.to_string(),
+            name: "Test".to_string(),
+            surname: "Student".to_string(),
+            patronymic: None,
+            email: email.to_string(),
+            password: "correct horse battery".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_registrations_with_one_email_admit_exactly_one() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let config = Arc::new(testing::config(&[]));
+
+        let mut attempts = tokio::task::JoinSet::new();
+        for i in 0..8 {
+            // the same address, in different cases
+            let email = if i % 2 == 0 {
+                "twin@example.org"
+            } else {
+                "TWIN@example.org"
+            };
+            let student = new_student(&format!("twin{}", i), email);
+            let (pool, config) = (pool.clone(), config.clone());
+            attempts.spawn(async move {
+                create_account(
+                    student,
+                    Role::Student,
+                    &pool,
+                    &crate::mail::LogMailer,
+                    &config,
+                )
+                .await
+            });
+        }
+        let mut results = Vec::new();
+        while let Some(result) = attempts.join_next().await {
+            results.push(result.unwrap());
+        }
+
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        for result in results.iter().filter_map(|result| result.as_ref().err()) {
+            assert!(
+                matches!(result, Error::UserAlreadyExists { .. }),
+                "{:?}",
+                result
+            );
+        }
+        let stored: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM users WHERE lower(email) = 'twin@example.org'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(stored, 1);
+    }
+
+    #[tokio::test]
+    async fn regenerates_a_colliding_session_id() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let expires_at = Utc::now() + Duration::hours(1);
+        let taken = testing::insert_session(&pool, student, expires_at).await;
+
+        let mut ids = vec!["fresh".to_string(), taken.clone()];
+        let ssid = insert_session(
+            &mut pool.acquire().await.unwrap(),
+            student,
+            expires_at,
+            &ClientInfo::default(),
+            || ids.pop().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(ssid.as_deref(), Some("fresh"));
+        assert!(session_exists(&pool, &taken).await);
+        assert!(session_exists(&pool, "fresh").await);
+    }
+
+    #[tokio::test]
+    async fn gives_up_when_every_session_id_collides() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let expires_at = Utc::now() + Duration::hours(1);
+        let taken = testing::insert_session(&pool, student, expires_at).await;
+
+        let mut drawn = 0;
+        let result = insert_session(
+            &mut pool.acquire().await.unwrap(),
+            student,
+            expires_at,
+            &ClientInfo::default(),
+            || {
+                drawn += 1;
+                taken.clone()
+            },
+        )
+        .await;
+
+        assert!(matches!(result, Ok(None)));
+        assert_eq!(drawn, SSID_INSERT_ATTEMPTS);
+    }
+
+    async fn drop_as(pool: &PgPool, ssid: &str, uuid: Uuid) -> serde_json::Value {
+        testing::json(
+            drop_session(
+                ClientInfo::default(),
+                JsonBody(EnsureSession {
+                    ssid: ssid.to_string(),
+                    value: DropSession { uuid },
+                }),
+                Extension(pool.clone()),
+                Extension(Arc::new(testing::config(&[]))),
+                Extension(SessionBudget::default()),
+            )
+            .await,
+        )
+    }
+
+    #[tokio::test]
+    async fn reports_each_drop_outcome() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let owner = testing::insert_user(&pool, "owner", "password").await;
+        let other = testing::insert_user(&pool, "other", "password").await;
+        let ssid = testing::insert_session(&pool, owner, Utc::now() + Duration::hours(1)).await;
+
+        let not_owned = drop_as(&pool, &ssid, other).await;
+        assert_eq!(not_owned["outcome"], "NotOwned");
+        assert_eq!(not_owned["drop_success"], false);
+        assert!(session_exists(&pool, &ssid).await);
+
+        let dropped = drop_as(&pool, &ssid, owner).await;
+        assert_eq!(dropped["outcome"], "Dropped");
+        assert_eq!(dropped["drop_success"], true);
+        assert!(!session_exists(&pool, &ssid).await);
+
+        let not_found = drop_as(&pool, &ssid, owner).await;
+        assert_eq!(not_found["auth_result"], "InvalidSession");
+        assert_eq!(not_found["outcome"], "NotFound");
+        assert_eq!(not_found["drop_success"], false);
+    }
+
+    fn config_with_blocklist() -> Config {
+        let path = testing::temp_dir().join("blocklist.txt");
+        std::fs::write(&path, "password123\nqwerty\n\n").unwrap();
+        testing::config(&[("PASSWORD_BLOCKLIST_PATH", path.to_str().unwrap())])
+    }
+
+    #[test]
+    fn rejects_blocklisted_passwords_in_any_case() {
+        let config = config_with_blocklist();
+
+        for password in ["password123", "PassWord123", "qwerty"] {
+            assert!(matches!(
+                check_new_password(password, &config),
+                Err(Error::WeakPassword { .. })
+            ));
+        }
+        assert!(check_new_password("correct horse battery", &config).is_ok());
+    }
+
+    #[tokio::test]
+    async fn registration_refuses_blocklisted_passwords() {
+        let config = config_with_blocklist();
+        let mut student = new_student("student", "student@example.org");
+        student.password = "Qwerty".to_string();
+
+        let result = create_account(
+            student,
+            Role::Student,
+            &testing::lazy_pool(),
+            &crate::mail::LogMailer,
+            &config,
+        )
+        .await;
+
+        assert!(matches!(result, Err(Error::WeakPassword { .. })));
+    }
+
+    #[test]
+    fn binds_sessions_to_the_configured_parts_of_the_client() {
+        let session = StudentSession {
+            ssid: generate_ssid(),
+            belongs_to: Uuid::new_v4(),
+            expires_at: Utc::now(),
+            ip: Some("203.0.113.7".to_string()),
+            user_agent: Some("Firefox".to_string()),
+        };
+        let client = |ip: &str, ua: &str| ClientInfo {
+            ip: ip.parse().ok(),
+            user_agent: Some(ua.to_string()),
+        };
+        let same = client("203.0.113.7", "Firefox");
+        let same_network = client("203.0.113.200", "Firefox");
+        let other_network = client("198.51.100.2", "Firefox");
+        let other_agent = client("203.0.113.7", "Chrome");
+
+        // (mode, same, same network, other network, other agent)
+        for (mode, expected) in [
+            ("none", [true, true, true, true]),
+            ("ip", [true, true, false, true]),
+            ("ua", [true, true, true, false]),
+            ("both", [true, true, false, false]),
+        ] {
+            let config = testing::config(&[("SESSION_BIND", mode)]);
+            let matches = [&same, &same_network, &other_network, &other_agent]
+                .map(|client| client.matches_session(&session, &config));
+            assert_eq!(matches, expected, "SESSION_BIND={}", mode);
+        }
+    }
+
+    #[tokio::test]
+    async fn binds_sessions_to_the_forwarded_client_address() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let uuid = testing::insert_user(&pool, "student", "correct horse battery").await;
+        let app = testing::app(testing::config(&[("SESSION_BIND", "ip")]), pool).await;
+        let from = |ip: &str, mut request: axum::http::Request<axum::body::Body>| {
+            request
+                .headers_mut()
+                .insert("x-forwarded-for", ip.parse().unwrap());
+            request
+        };
+
+        let login = testing::send(
+            &app,
+            from(
+                "203.0.113.7",
+                testing::post(
+                    "/session/login",
+                    serde_json::json!({ "uuid": uuid, "password": "correct horse battery" }),
+                ),
+            ),
+        )
+        .await;
+        let ssid = testing::body_json(login).await["session_id"]
+            .as_str()
+            .expect("login succeeds")
+            .to_string();
+        let sync = |ip: &str| {
+            from(
+                ip,
+                testing::post("/session/sync", serde_json::json!({ "ssid": ssid })),
+            )
+        };
+
+        let response = testing::send(&app, sync("203.0.113.50")).await;
+        assert_eq!(testing::body_json(response).await["auth_result"], "Success");
+
+        // all clients connect through the same proxy, the forwarded address decides
+        let response = testing::send(&app, sync("198.51.100.2")).await;
+        assert_eq!(
+            testing::body_json(response).await["auth_result"],
+            "InvalidSession"
+        );
+    }
+
+    #[tokio::test]
+    async fn every_authenticated_route_spends_from_the_session_budget() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let uuid = testing::insert_user(&pool, "student", "password").await;
+        let ssid = testing::insert_session(&pool, uuid, Utc::now() + Duration::hours(1)).await;
+        let other = testing::insert_session(&pool, uuid, Utc::now() + Duration::hours(1)).await;
+        let config = testing::config(&[
+            ("SESSION_RATE_PER_MINUTE", "1"),
+            ("SESSION_RATE_BURST", "2"),
+        ]);
+        let app = testing::app(config, pool).await;
+        let with = |uri: &str, ssid: &str| testing::post(uri, serde_json::json!({ "ssid": ssid }));
+
+        let response = testing::send(&app, with("/session/sync", &ssid)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = testing::send(&app, with("/student/2fa/enroll", &ssid)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        for uri in ["/parent/children", "/student/2fa/enroll", "/session/sync"] {
+            let response = testing::send(&app, with(uri, &ssid)).await;
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS, "{}", uri);
+            assert!(response.headers().contains_key("retry-after"));
+            assert_eq!(testing::body_json(response).await["error"], "RateLimited");
+        }
+
+        let response = testing::send(&app, with("/session/sync", &other)).await;
+        assert_eq!(testing::body_json(response).await["auth_result"], "Success");
+    }
+
+    #[tokio::test]
+    async fn rejected_sessions_spend_nothing() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let uuid = testing::insert_user(&pool, "student", "password").await;
+        let config = testing::config(&[
+            ("SESSION_BIND", "ip"),
+            ("SESSION_RATE_PER_MINUTE", "1"),
+            ("SESSION_RATE_BURST", "2"),
+        ]);
+        let app = testing::app(config, pool).await;
+        let login = testing::post(
+            "/session/login",
+            serde_json::json!({ "uuid": uuid, "password": "password" }),
+        );
+        let login = testing::send_from(&app, "198.51.100.1:40000", login).await;
+        let ssid = testing::body_json(login).await["session_id"]
+            .as_str()
+            .expect("login succeeds")
+            .to_string();
+        let sync = |ssid: &str| testing::post("/session/sync", serde_json::json!({ "ssid": ssid }));
+
+        // neither made-up ids nor a real one used from elsewhere eat into the owner's budget
+        for (peer, ssid) in [
+            ("198.51.100.1:40000", "forged"),
+            ("203.0.113.9:40000", &ssid),
+        ]
+        .into_iter()
+        .cycle()
+        .take(6)
+        {
+            let response = testing::send_from(&app, peer, sync(ssid)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                testing::body_json(response).await["auth_result"],
+                "InvalidSession"
+            );
+        }
+        for _ in 0..2 {
+            let response = testing::send_from(&app, "198.51.100.1:40000", sync(&ssid)).await;
+            assert_eq!(testing::body_json(response).await["auth_result"], "Success");
+        }
+        let response = testing::send_from(&app, "198.51.100.1:40000", sync(&ssid)).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn access_tokens_share_the_budget_of_their_student() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let vars = [
+            ("AUTH_MODE", "jwt"),
+            ("SESSION_RATE_PER_MINUTE", "1"),
+            ("SESSION_RATE_BURST", "2"),
+        ];
+        let app = testing::app(testing::config(&vars), pool).await;
+        // tokens minted with different lifetimes differ, like a token and its refreshed successor
+        let (first, _) = mint_access_token(student, &testing::config(&vars)).unwrap();
+        let (second, _) = mint_access_token(
+            student,
+            &testing::config(&[("ACCESS_TOKEN_TTL_MINUTES", "30")]),
+        )
+        .unwrap();
+        assert_ne!(first, second);
+        let sync = |ssid: &str| testing::post("/session/sync", serde_json::json!({ "ssid": ssid }));
+
+        for token in [&first, &second] {
+            let response = testing::send(&app, sync(token)).await;
+            assert_eq!(testing::body_json(response).await["auth_result"], "Success");
+        }
+        let response = testing::send(&app, sync(&second)).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn matches_email_domains_and_wildcard_subdomains() {
+        let allowed = vec![
+            "school.example".to_string(),
+            "*.district.example".to_string(),
+        ];
+
+        assert!(email_domain_allowed("pupil@School.Example", &allowed));
+        assert!(email_domain_allowed(
+            "pupil@north.district.example",
+            &allowed
+        ));
+        assert!(email_domain_allowed("pupil@a.b.district.example", &allowed));
+        assert!(!email_domain_allowed("pupil@district.example", &allowed));
+        assert!(!email_domain_allowed(
+            "pupil@evildistrict.example",
+            &allowed
+        ));
+        assert!(!email_domain_allowed("pupil@sub.school.example", &allowed));
+        assert!(!email_domain_allowed("pupil@school.example.com", &allowed));
+        assert!(!email_domain_allowed("no-at-sign", &allowed));
+
+        assert!(email_domain_allowed("anyone@anywhere.example", &[]));
+    }
+
+    #[tokio::test]
+    async fn registration_honours_the_email_domain_allowlist() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let config =
+            testing::config(&[("ALLOWED_EMAIL_DOMAINS", "school.example, *.school.example")]);
+
+        let registered = create_account(
+            new_student("on_domain", "pupil@class1.school.example"),
+            Role::Student,
+            &pool,
+            &crate::mail::LogMailer,
+            &config,
+        )
+        .await;
+        assert!(registered.is_ok());
+
+        let rejected = create_account(
+            new_student("off_domain", "pupil@elsewhere.example"),
+            Role::Student,
+            &pool,
+            &crate::mail::LogMailer,
+            &config,
+        )
+        .await;
+        assert!(matches!(rejected, Err(Error::EmailDomainNotAllowed { .. })));
+        let stored: i64 = sqlx::query_scalar("SELECT count(*) FROM users")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(stored, 1);
+    }
+
+    async fn sessions_of(pool: &PgPool, student: Uuid) -> Vec<String> {
+        sqlx::query_scalar("SELECT ssid FROM user_sessions WHERE belongs_to = $1")
+            .bind(student)
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn single_session_login_repairs_duplicate_sessions() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let config = testing::config(&[("SESSION_MODE", "single")]);
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let older = testing::insert_session(&pool, student, Utc::now() + Duration::hours(1)).await;
+        let newer = testing::insert_session(&pool, student, Utc::now() + Duration::hours(2)).await;
+
+        let login = start_session(student, &ClientInfo::default(), &pool, &config)
+            .await
+            .unwrap();
+
+        assert_eq!(login.session_id, newer);
+        assert_eq!(sessions_of(&pool, student).await, vec![newer]);
+        assert!(!session_exists(&pool, &older).await);
+    }
+
+    #[tokio::test]
+    async fn login_retries_a_serialization_failure() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        // sequences are not transactional, so only the first insert fails
+        sqlx::query("CREATE SEQUENCE session_inserts")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            "CREATE FUNCTION fail_first_session_insert() RETURNS trigger AS $$ BEGIN \
+            IF nextval('session_inserts') = 1 THEN \
+            RAISE EXCEPTION 'forced' USING ERRCODE = 'serialization_failure'; \
+            END IF; RETURN NEW; END $$ LANGUAGE plpgsql",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "CREATE TRIGGER fail_first_session_insert BEFORE INSERT ON user_sessions \
+            FOR EACH ROW EXECUTE FUNCTION fail_first_session_insert()",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let app = testing::app(testing::config(&[]), pool.clone()).await;
+
+        let request = testing::post(
+            "/session/login",
+            serde_json::json!({ "uuid": student, "password": "password" }),
+        );
+        let body = testing::body_json(testing::send(&app, request).await).await;
+
+        assert_eq!(body["success"], true);
+        let ssid = body["session_id"].as_str().unwrap();
+        assert_eq!(sessions_of(&pool, student).await, vec![ssid.to_string()]);
+        let inserts: i64 = sqlx::query_scalar("SELECT last_value FROM session_inserts")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(inserts, 2);
+    }
+
+    #[tokio::test]
+    async fn login_gives_up_once_retries_run_out() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        sqlx::query(
+            "CREATE FUNCTION fail_session_insert() RETURNS trigger AS $$ BEGIN \
+            RAISE EXCEPTION 'forced' USING ERRCODE = 'serialization_failure'; END $$ \
+            LANGUAGE plpgsql",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query(
+            "CREATE TRIGGER fail_session_insert BEFORE INSERT ON user_sessions \
+            FOR EACH ROW EXECUTE FUNCTION fail_session_insert()",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let config = testing::config(&[("DB_MAX_RETRIES", "1")]);
+
+        let result = start_session(student, &ClientInfo::default(), &pool, &config).await;
+
+        assert!(matches!(result, Err(Error::ServiceUnavailable { .. })));
+    }
+
+    #[tokio::test]
+    async fn multi_session_login_always_mints_a_new_session() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let config = testing::config(&[("SESSION_MODE", "multi")]);
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let first = testing::insert_session(&pool, student, Utc::now() + Duration::hours(1)).await;
+        let second = testing::insert_session(&pool, student, Utc::now() + Duration::hours(2)).await;
+
+        let login = start_session(student, &ClientInfo::default(), &pool, &config)
+            .await
+            .unwrap();
+
+        assert!(login.session_id != first && login.session_id != second);
+        let mut sessions = sessions_of(&pool, student).await;
+        sessions.sort();
+        let mut expected = vec![first, second, login.session_id];
+        expected.sort();
+        assert_eq!(sessions, expected);
+    }
+
+    #[tokio::test]
+    async fn refused_login_leaves_existing_sessions_alone() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let config = testing::config(&[
+            ("SESSION_MODE", "single"),
+            ("SESSION_BIND", "ua"),
+            ("MAX_TOTAL_SESSIONS", "1"),
+            ("SESSION_CEILING_POLICY", "reject"),
+        ]);
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let other = testing::insert_user(&pool, "other", "password").await;
+        let bound = testing::insert_session(&pool, student, Utc::now() + Duration::hours(1)).await;
+        sqlx::query("UPDATE user_sessions SET user_agent = 'Firefox' WHERE ssid = $1")
+            .bind(&bound)
+            .execute(&pool)
+            .await
+            .unwrap();
+        testing::insert_session(&pool, other, Utc::now() + Duration::hours(1)).await;
+        let client = ClientInfo {
+            ip: None,
+            user_agent: Some("Chrome".to_string()),
+        };
+
+        // the session bound to Firefox is dropped, then the ceiling refuses a new one
+        let err = start_session(student, &client, &pool, &config)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, Error::ServiceUnavailable { .. }));
+        assert!(session_exists(&pool, &bound).await);
+    }
+
+    #[tokio::test]
+    async fn syncs_the_session_clock_over_get() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let ssid = testing::insert_session(&pool, student, Utc::now() + Duration::days(2)).await;
+        let app = testing::app(testing::config(&[]), pool).await;
+
+        let mut request = testing::get("/session/sync");
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, format!("Bearer {}", ssid).parse().unwrap());
+        let body = testing::body_json(testing::send(&app, request).await).await;
+
+        assert_eq!(body["auth_result"], "Success");
+        let time = |field: &str| {
+            body[field]
+                .as_str()
+                .and_then(|time| time.parse::<DateTime<Utc>>().ok())
+                .unwrap_or_else(|| panic!("`{}` is a timestamp", field))
+        };
+        assert!(time("expires_at") > time("server_time"));
+
+        let body =
+            testing::body_json(testing::send(&app, testing::get("/session/sync")).await).await;
+        assert_eq!(body["auth_result"], "InvalidSession");
+        assert!(body.get("server_time").is_none());
+    }
+
+    #[tokio::test]
+    async fn jwt_mode_issues_renewable_access_tokens() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let app = testing::app(testing::config(&[("AUTH_MODE", "jwt")]), pool.clone()).await;
+        let post = |uri: &str, body: serde_json::Value| {
+            let app = app.clone();
+            let request = testing::post(uri, body);
+            async move { testing::body_json(testing::send(&app, request).await).await }
+        };
+
+        let login = post(
+            "/session/login",
+            serde_json::json!({ "uuid": student, "password": "password" }),
+        )
+        .await;
+        let access_token = login["session_id"].as_str().unwrap().to_string();
+        let refresh_token = login["refresh_token"].as_str().unwrap().to_string();
+        assert!(verify_access_token(&access_token, &testing::config(&[])).is_ok());
+        assert!(sessions_of(&pool, student).await.is_empty());
+
+        let sync = post("/session/sync", serde_json::json!({ "ssid": access_token })).await;
+        assert_eq!(sync["auth_result"], "Success");
+        let sync = post("/session/sync", serde_json::json!({ "ssid": "forged" })).await;
+        assert_eq!(sync["auth_result"], "InvalidSession");
+
+        let renewed = post(
+            "/student/refresh",
+            serde_json::json!({ "refresh_token": refresh_token }),
+        )
+        .await;
+        assert_eq!(renewed["student_id"], student.to_string());
+        let renewed_refresh = renewed["refresh_token"].as_str().unwrap().to_string();
+        assert_ne!(renewed_refresh, refresh_token);
+
+        // refresh tokens are single-use
+        let reused = post(
+            "/student/refresh",
+            serde_json::json!({ "refresh_token": refresh_token }),
+        )
+        .await;
+        assert_eq!(reused["error"], "AuthenticationFailure");
+
+        // dropping the session revokes the remaining refresh token
+        let dropped = post(
+            "/session/drop",
+            serde_json::json!({ "ssid": access_token, "uuid": student }),
+        )
+        .await;
+        assert_eq!(dropped["outcome"], "Dropped");
+        let revoked = post(
+            "/student/refresh",
+            serde_json::json!({ "refresh_token": renewed_refresh }),
+        )
+        .await;
+        assert_eq!(revoked["error"], "AuthenticationFailure");
+    }
+
+    #[tokio::test]
+    async fn access_tokens_skip_the_user_and_binding_checks_until_refreshed() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let config = testing::config(&[("AUTH_MODE", "jwt"), ("SESSION_BIND", "ip")]);
+        // a student that does not exist, as if deleted after logging in
+        let gone = Uuid::new_v4();
+        let (access_token, _) = mint_access_token(gone, &config).unwrap();
+        sqlx::query(
+            "INSERT INTO refresh_tokens (token, belongs_to, expires_at) VALUES ($1, $2, $3)",
+        )
+        .bind("refresh")
+        .bind(gone)
+        .bind(Utc::now() + Duration::days(1))
+        .execute(&pool)
+        .await
+        .unwrap();
+        let app = testing::app(config, pool).await;
+
+        for peer in ["198.51.100.1:40000", "203.0.113.9:40000"] {
+            let sync = testing::post("/session/sync", serde_json::json!({ "ssid": access_token }));
+            let response = testing::send_from(&app, peer, sync).await;
+            assert_eq!(testing::body_json(response).await["auth_result"], "Success");
+        }
+
+        let refresh = testing::post(
+            "/student/refresh",
+            serde_json::json!({ "refresh_token": "refresh" }),
+        );
+        let response = testing::send(&app, refresh).await;
+        assert_eq!(
+            testing::body_json(response).await["error"],
+            "UserDoesNotExist"
+        );
+    }
+
+    #[test]
+    fn access_tokens_expire() {
+        let config = testing::config(&[("ACCESS_TOKEN_TTL_MINUTES", "-5")]);
+
+        let (token, _) = mint_access_token(Uuid::new_v4(), &config).unwrap();
+
+        assert!(verify_access_token(&token, &config).is_err());
+    }
+}
@@ -0,0 +1,693 @@
+use crate::ratelimit::RateLimitKey;
+
+use anyhow::{anyhow, Context};
+use jsonwebtoken::jwk::JwkSet;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const MIN_SECRET_LEN: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub database_url: String,
+    pub db_max_retries: u32,
+    pub db_max_connections: u32,
+    pub db_acquire_warn_ms: u64,
+    pub db_slow_query_warn_ms: u64,
+    pub db_startup_retries: u32,
+    pub db_startup_retry_delay_ms: u64,
+    pub static_dir: Option<PathBuf>,
+    pub max_body_bytes: usize,
+    pub msgpack: bool,
+    pub public_cache_max_age: u64,
+    pub security_headers: bool,
+    pub frame_options: String,
+    pub content_security_policy: String,
+    pub hsts_max_age: Option<u64>,
+    pub https_policy: HttpsPolicy,
+    pub client_ip_header: ClientIpHeader,
+    pub session_bind: SessionBind,
+    pub session_bind_ipv4_prefix: u32,
+    pub session_bind_ipv6_prefix: u32,
+    pub max_total_sessions: i64,
+    pub session_ceiling_policy: SessionCeilingPolicy,
+    pub session_mode: SessionMode,
+    pub auth_mode: AuthMode,
+    pub access_token_ttl_minutes: i64,
+    pub refresh_token_ttl_days: i64,
+    pub public_url: String,
+    pub mail_transport: MailTransport,
+    pub mail_from: String,
+    pub sendmail_path: PathBuf,
+    pub password_reset_ttl_minutes: i64,
+    pub password_reset_rate_limit: u32,
+    pub password_reset_rate_window_secs: u64,
+    pub require_verified_email: bool,
+    pub email_verification_grace_hours: i64,
+    pub email_verification_ttl_hours: i64,
+    pub totp_issuer: String,
+    pub lockout_threshold: u32,
+    pub lockout_duration_minutes: i64,
+    pub parent_link_code_ttl_hours: i64,
+    pub strict_uuid_v4: bool,
+    pub password_verify_min_ms: u64,
+    pub diary_store: DiaryStoreKind,
+    pub diary_dir: PathBuf,
+    pub s3_bucket: Option<String>,
+    pub s3_region: String,
+    pub s3_endpoint: Option<String>,
+    pub token_signing_secret: Vec<u8>,
+    /// Whether `token_signing_secret` was generated for this run rather than configured.
+    pub ephemeral_signing_secret: bool,
+    pub token_signing_key_id: String,
+    pub token_previous_keys: PreviousKeys,
+    pub scoped_token_ttl_minutes: i64,
+    pub password_blocklist: HashSet<String>,
+    pub allowed_email_domains: Vec<String>,
+    pub oidc_issuer: Option<String>,
+    pub oidc_audience: Option<String>,
+    pub oidc_jwks: Option<JwkSet>,
+    pub login_rate_limit: u32,
+    pub login_rate_window_secs: u64,
+    pub login_rate_key: RateLimitKey,
+    pub register_rate_limit: u32,
+    pub register_rate_window_secs: u64,
+    pub register_rate_key: RateLimitKey,
+    pub session_rate_per_minute: u32,
+    pub session_rate_burst: u32,
+}
+
+/// Which properties of the creating client a session is bound to. Access tokens are never bound.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SessionBind {
+    None,
+    Ip,
+    UserAgent,
+    Both,
+}
+
+impl SessionBind {
+    pub fn ip(self) -> bool {
+        matches!(self, Self::Ip | Self::Both)
+    }
+
+    pub fn user_agent(self) -> bool {
+        matches!(self, Self::UserAgent | Self::Both)
+    }
+}
+
+impl FromStr for SessionBind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "ip" => Ok(Self::Ip),
+            "ua" => Ok(Self::UserAgent),
+            "both" => Ok(Self::Both),
+            other => Err(format!(
+                "expected `none`, `ip`, `ua` or `both`, got `{}`",
+                other
+            )),
+        }
+    }
+}
+
+/// Retired token signing secrets by key id, still accepted when verifying.
+/// Parsed from comma-separated `id:secret` pairs.
+#[derive(Debug, Clone, Default)]
+pub struct PreviousKeys(pub HashMap<String, Vec<u8>>);
+
+impl FromStr for PreviousKeys {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| match pair.split_once(':') {
+                Some((id, secret)) if !id.is_empty() && !secret.is_empty() => {
+                    Ok((id.to_string(), secret.as_bytes().to_vec()))
+                }
+                _ => Err(format!("expected `id:secret`, got `{}`", pair)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// What happens to requests that did not arrive over HTTPS.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HttpsPolicy {
+    /// Serve them as usual.
+    Off,
+    /// Answer with a `301` to the same URL over HTTPS.
+    Redirect,
+    /// Refuse them with `403 Forbidden`.
+    Reject,
+}
+
+impl FromStr for HttpsPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "redirect" => Ok(Self::Redirect),
+            "reject" => Ok(Self::Reject),
+            other => Err(format!(
+                "expected `off`, `redirect` or `reject`, got `{}`",
+                other
+            )),
+        }
+    }
+}
+
+/// Where the address of the client is read from. The server only listens on loopback,
+/// so every connection comes from the reverse proxy in front of it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ClientIpHeader {
+    /// The last entry of `X-Forwarded-For`, which is the one our proxy appended.
+    XForwardedFor,
+    /// The `for` parameter of the last element of the RFC 7239 `Forwarded` header.
+    Forwarded,
+    /// The peer address of the connection, for running without a proxy.
+    None,
+}
+
+impl FromStr for ClientIpHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "x-forwarded-for" => Ok(Self::XForwardedFor),
+            "forwarded" => Ok(Self::Forwarded),
+            "none" => Ok(Self::None),
+            other => Err(format!(
+                "expected `x-forwarded-for`, `forwarded` or `none`, got `{}`",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DiaryStoreKind {
+    Local,
+    S3,
+}
+
+impl FromStr for DiaryStoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "local" => Ok(Self::Local),
+            "s3" => Ok(Self::S3),
+            other => Err(format!("expected `local` or `s3`, got `{}`", other)),
+        }
+    }
+}
+
+/// What `login_student` does once `MAX_TOTAL_SESSIONS` live sessions exist.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SessionCeilingPolicy {
+    /// Drop the sessions closest to expiry to make room.
+    Evict,
+    /// Refuse the login with `503 Service Unavailable`.
+    Reject,
+}
+
+impl FromStr for SessionCeilingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "evict" => Ok(Self::Evict),
+            "reject" => Ok(Self::Reject),
+            other => Err(format!("expected `evict` or `reject`, got `{}`", other)),
+        }
+    }
+}
+
+/// How many sessions a student may hold at once.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SessionMode {
+    /// One session per student, reused by later logins from the same client.
+    Single,
+    /// Every login gets a fresh session.
+    Multi,
+}
+
+impl FromStr for SessionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "single" => Ok(Self::Single),
+            "multi" => Ok(Self::Multi),
+            other => Err(format!("expected `single` or `multi`, got `{}`", other)),
+        }
+    }
+}
+
+/// What login hands out and what authenticated endpoints accept in the `ssid` field.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AuthMode {
+    /// Session ids looked up in `user_sessions` on every request.
+    Session,
+    /// Short-lived signed access tokens, renewed through `/student/refresh`. They are checked
+    /// without the database, so `SESSION_BIND` does not apply to them and they keep working until
+    /// `ACCESS_TOKEN_TTL_MINUTES` runs out even if their user is deleted. Refreshing checks the user.
+    Jwt,
+}
+
+impl FromStr for AuthMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "session" => Ok(Self::Session),
+            "jwt" => Ok(Self::Jwt),
+            other => Err(format!("expected `session` or `jwt`, got `{}`", other)),
+        }
+    }
+}
+
+/// How outgoing mail is delivered.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MailTransport {
+    /// Write messages to the log instead of sending them, for development.
+    Log,
+    /// Pipe messages to the local `sendmail` binary at `SENDMAIL_PATH`.
+    Sendmail,
+}
+
+impl FromStr for MailTransport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "log" => Ok(Self::Log),
+            "sendmail" => Ok(Self::Sendmail),
+            other => Err(format!("expected `log` or `sendmail`, got `{}`", other)),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Self> {
+        Self::from_vars(|key| std::env::var(key).ok())
+    }
+
+    /// Builds the configuration from whatever `var` returns for each setting.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        let var = &var;
+        let session_rate_per_minute = env_or(var, "SESSION_RATE_PER_MINUTE", 120)?;
+        let session_rate_burst = env_or(var, "SESSION_RATE_BURST", 30)?;
+        Ok(Self {
+            database_url: var("POSTGRES_DATABASE")
+                .context("`POSTGRES_DATABASE` environment variable not provided!")?,
+            db_max_retries: env_or(var, "DB_MAX_RETRIES", 3)?,
+            db_max_connections: env_or(var, "DB_MAX_CONNECTIONS", 5)?,
+            db_acquire_warn_ms: env_or(var, "DB_ACQUIRE_WARN_MS", 250)?,
+            db_slow_query_warn_ms: env_or(var, "DB_SLOW_QUERY_WARN_MS", 1000)?,
+            db_startup_retries: env_or(var, "DB_STARTUP_RETRIES", 10)?,
+            db_startup_retry_delay_ms: env_or(var, "DB_STARTUP_RETRY_DELAY_MS", 500)?,
+            static_dir: var("STATIC_DIR").map(PathBuf::from),
+            max_body_bytes: env_or(var, "MAX_BODY_BYTES", 64 * 1024)?,
+            msgpack: env_or(var, "MSGPACK", true)?,
+            public_cache_max_age: env_or(var, "PUBLIC_CACHE_MAX_AGE", 0)?,
+            security_headers: env_or(var, "SECURITY_HEADERS", false)?,
+            frame_options: env_or(var, "SECURITY_FRAME_OPTIONS", "DENY".to_string())?,
+            content_security_policy: env_or(
+                var,
+                "SECURITY_CSP",
+                "default-src 'self'; frame-ancestors 'none'".to_string(),
+            )?,
+            hsts_max_age: env_opt(var, "SECURITY_HSTS_MAX_AGE")?,
+            https_policy: env_or(var, "HTTPS_POLICY", HttpsPolicy::Off)?,
+            client_ip_header: env_or(var, "CLIENT_IP_HEADER", ClientIpHeader::XForwardedFor)?,
+            session_bind: env_or(var, "SESSION_BIND", SessionBind::None)?,
+            session_bind_ipv4_prefix: env_or(var, "SESSION_BIND_IPV4_PREFIX", 24)?,
+            session_bind_ipv6_prefix: env_or(var, "SESSION_BIND_IPV6_PREFIX", 64)?,
+            max_total_sessions: env_or(var, "MAX_TOTAL_SESSIONS", 0)?,
+            session_ceiling_policy: env_or(
+                var,
+                "SESSION_CEILING_POLICY",
+                SessionCeilingPolicy::Reject,
+            )?,
+            session_mode: env_or(var, "SESSION_MODE", SessionMode::Single)?,
+            auth_mode: env_or(var, "AUTH_MODE", AuthMode::Session)?,
+            access_token_ttl_minutes: env_or(var, "ACCESS_TOKEN_TTL_MINUTES", 15)?,
+            refresh_token_ttl_days: env_or(var, "REFRESH_TOKEN_TTL_DAYS", 30)?,
+            public_url: env_or(var, "PUBLIC_URL", "http://127.0.0.1:3000".to_string())?,
+            mail_transport: env_or(var, "MAIL_TRANSPORT", MailTransport::Log)?,
+            mail_from: env_or(
+                var,
+                "MAIL_FROM",
+                "OpenDiary <no-reply@localhost>".to_string(),
+            )?,
+            sendmail_path: env_or(var, "SENDMAIL_PATH", PathBuf::from("/usr/sbin/sendmail"))?,
+            password_reset_ttl_minutes: env_or(var, "PASSWORD_RESET_TTL_MINUTES", 30)?,
+            password_reset_rate_limit: env_or(var, "PASSWORD_RESET_RATE_LIMIT", 5)?,
+            password_reset_rate_window_secs: env_or(var, "PASSWORD_RESET_RATE_WINDOW_SECS", 3600)?,
+            require_verified_email: env_or(var, "REQUIRE_VERIFIED_EMAIL", true)?,
+            email_verification_grace_hours: env_or(var, "EMAIL_VERIFICATION_GRACE_HOURS", 72)?,
+            email_verification_ttl_hours: env_or(var, "EMAIL_VERIFICATION_TTL_HOURS", 48)?,
+            totp_issuer: env_or(var, "TOTP_ISSUER", "OpenDiary".to_string())?,
+            lockout_threshold: env_or(var, "LOCKOUT_THRESHOLD", 5)?,
+            lockout_duration_minutes: env_or(var, "LOCKOUT_DURATION_MINUTES", 5)?,
+            parent_link_code_ttl_hours: env_or(var, "PARENT_LINK_CODE_TTL_HOURS", 72)?,
+            strict_uuid_v4: env_or(var, "STRICT_UUID_V4", false)?,
+            password_verify_min_ms: env_or(var, "PASSWORD_VERIFY_MIN_MS", 0)?,
+            diary_store: env_or(var, "DIARY_STORE", DiaryStoreKind::Local)?,
+            diary_dir: env_or(var, "DIARY_DIR", PathBuf::from("diary"))?,
+            s3_bucket: env_opt(var, "S3_BUCKET")?,
+            s3_region: env_or(var, "S3_REGION", "us-east-1".to_string())?,
+            s3_endpoint: env_opt(var, "S3_ENDPOINT")?,
+            token_signing_secret: token_signing_secret(var),
+            ephemeral_signing_secret: var("TOKEN_SIGNING_SECRET")
+                .is_none_or(|secret| secret.is_empty()),
+            token_signing_key_id: env_or(var, "TOKEN_SIGNING_KEY_ID", "primary".to_string())?,
+            token_previous_keys: env_or(var, "TOKEN_PREVIOUS_SECRETS", PreviousKeys::default())?,
+            password_blocklist: match env_opt::<PathBuf>(var, "PASSWORD_BLOCKLIST_PATH")? {
+                Some(path) => load_password_blocklist(&path)?,
+                None => HashSet::new(),
+            },
+            allowed_email_domains: env_or(var, "ALLOWED_EMAIL_DOMAINS", String::new())?
+                .split(',')
+                .map(|domain| domain.trim().to_lowercase())
+                .filter(|domain| !domain.is_empty())
+                .collect(),
+            oidc_issuer: env_opt(var, "OIDC_ISSUER")?,
+            oidc_audience: env_opt(var, "OIDC_AUDIENCE")?,
+            oidc_jwks: match env_opt::<PathBuf>(var, "OIDC_JWKS_PATH")? {
+                Some(path) => Some(load_jwks(&path)?),
+                None => None,
+            },
+            login_rate_limit: env_or(var, "LOGIN_RATE_LIMIT", 10)?,
+            login_rate_window_secs: env_or(var, "LOGIN_RATE_WINDOW_SECS", 60)?,
+            login_rate_key: env_or(var, "LOGIN_RATE_KEY", RateLimitKey::Both)?,
+            register_rate_limit: env_or(var, "REGISTER_RATE_LIMIT", 5)?,
+            register_rate_window_secs: env_or(var, "REGISTER_RATE_WINDOW_SECS", 3600)?,
+            register_rate_key: env_or(var, "REGISTER_RATE_KEY", RateLimitKey::Ip)?,
+            session_rate_per_minute,
+            session_rate_burst,
+            scoped_token_ttl_minutes: env_or(var, "SCOPED_TOKEN_TTL_MINUTES", 5)?,
+        })
+    }
+
+    /// Reports settings that parse fine on their own but are missing, weak or contradict each other,
+    /// any of which makes `--check-config` fail.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if !self.ephemeral_signing_secret && self.token_signing_secret.len() < MIN_SECRET_LEN {
+            problems.push(format!(
+                "`TOKEN_SIGNING_SECRET` is shorter than {} bytes",
+                MIN_SECRET_LEN
+            ));
+        }
+        for (id, secret) in &self.token_previous_keys.0 {
+            if id == &self.token_signing_key_id {
+                problems.push(format!(
+                    "`TOKEN_PREVIOUS_SECRETS` reuses the active key id `{}`",
+                    id
+                ));
+            } else if secret.len() < MIN_SECRET_LEN {
+                problems.push(format!(
+                    "Previous signing secret `{}` is shorter than {} bytes",
+                    id, MIN_SECRET_LEN
+                ));
+            }
+        }
+        if self.oidc_issuer.is_some() && (self.oidc_audience.is_none() || self.oidc_jwks.is_none())
+        {
+            problems.push(
+                "`OIDC_ISSUER` is set but `OIDC_AUDIENCE` or `OIDC_JWKS_PATH` is missing, so SSO is disabled"
+                    .to_string(),
+            );
+        }
+        if self.auth_mode == AuthMode::Jwt
+            && (self.access_token_ttl_minutes <= 0 || self.refresh_token_ttl_days <= 0)
+        {
+            problems.push(
+                "`ACCESS_TOKEN_TTL_MINUTES` and `REFRESH_TOKEN_TTL_DAYS` must be positive"
+                    .to_string(),
+            );
+        }
+        if self.mail_transport == MailTransport::Sendmail && !self.sendmail_path.is_file() {
+            problems.push(format!(
+                "`SENDMAIL_PATH` `{}` does not exist",
+                self.sendmail_path.display()
+            ));
+        }
+        if self.password_reset_ttl_minutes <= 0 {
+            problems.push("`PASSWORD_RESET_TTL_MINUTES` must be positive".to_string());
+        }
+        if self.email_verification_ttl_hours <= 0 {
+            problems.push("`EMAIL_VERIFICATION_TTL_HOURS` must be positive".to_string());
+        }
+        if self.lockout_threshold > 0 && self.lockout_duration_minutes <= 0 {
+            problems.push("`LOCKOUT_DURATION_MINUTES` must be positive".to_string());
+        }
+        if self.parent_link_code_ttl_hours <= 0 {
+            problems.push("`PARENT_LINK_CODE_TTL_HOURS` must be positive".to_string());
+        }
+        if self.db_max_connections == 0 {
+            problems.push("`DB_MAX_CONNECTIONS` must be at least 1".to_string());
+        }
+        if self.scoped_token_ttl_minutes <= 0 {
+            problems.push("`SCOPED_TOKEN_TTL_MINUTES` must be positive".to_string());
+        }
+        if let Some(dir) = &self.static_dir {
+            if !dir.join("index.html").is_file() {
+                problems.push(format!(
+                    "`STATIC_DIR` `{}` does not contain an index.html",
+                    dir.display()
+                ));
+            }
+        }
+        if self.diary_store == DiaryStoreKind::S3 {
+            if !cfg!(feature = "s3") {
+                problems.push(
+                    "`DIARY_STORE=s3` but the server was built without the `s3` feature"
+                        .to_string(),
+                );
+            }
+            if self.s3_bucket.is_none() {
+                problems.push("`DIARY_STORE=s3` requires `S3_BUCKET`".to_string());
+            }
+        }
+
+        problems
+    }
+
+    /// Reports settings that work but are likely not what a production deployment wants.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        if self.ephemeral_signing_secret {
+            warnings.push(
+                "`TOKEN_SIGNING_SECRET` is not set, issued tokens will not survive a restart"
+                    .to_string(),
+            );
+        }
+        if self.mail_transport == MailTransport::Log {
+            warnings.push(
+                "`MAIL_TRANSPORT` is `log`, emails are written to the log instead of being sent"
+                    .to_string(),
+            );
+        }
+        if self.hsts_max_age.is_some() && !self.security_headers {
+            warnings.push(
+                "`SECURITY_HSTS_MAX_AGE` is set but `SECURITY_HEADERS` is off, so it has no effect"
+                    .to_string(),
+            );
+        }
+        if self.diary_store != DiaryStoreKind::S3
+            && (self.s3_bucket.is_some() || self.s3_endpoint.is_some())
+        {
+            warnings.push("`S3_*` settings are ignored unless `DIARY_STORE=s3`".to_string());
+        }
+        if self.auth_mode == AuthMode::Jwt && self.session_bind != SessionBind::None {
+            warnings.push(
+                "`SESSION_BIND` has no effect on the access tokens issued with `AUTH_MODE=jwt`"
+                    .to_string(),
+            );
+        }
+
+        warnings
+    }
+}
+
+/// Loads the configuration and prints every problem and warning found, for `--check-config`.
+/// Returns whether the configuration is usable, warnings notwithstanding.
+pub fn check_config() -> bool {
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Configuration could not be loaded: {:#}", err);
+            return false;
+        }
+    };
+
+    let warnings = config.warnings();
+    if !warnings.is_empty() {
+        println!("Found {} configuration warning(s):", warnings.len());
+        for warning in &warnings {
+            println!("  - {}", warning);
+        }
+    }
+    let problems = config.problems();
+    if problems.is_empty() {
+        println!("Configuration OK");
+        return true;
+    }
+    println!("Found {} configuration problem(s):", problems.len());
+    for problem in &problems {
+        println!("  - {}", problem);
+    }
+    false
+}
+
+/// Reads `TOKEN_SIGNING_SECRET`, falling back to a random per-process secret,
+/// in which case issued tokens stop verifying after a restart.
+fn token_signing_secret(var: &dyn Fn(&str) -> Option<String>) -> Vec<u8> {
+    match var("TOKEN_SIGNING_SECRET") {
+        Some(secret) if !secret.is_empty() => secret.into_bytes(),
+        _ => {
+            log::warn!("`TOKEN_SIGNING_SECRET` not provided, using a random secret for this run");
+            rand::thread_rng().gen::<[u8; 32]>().to_vec()
+        }
+    }
+}
+
+/// Loads the issuer's signing keys from a JWKS document saved to disk.
+fn load_jwks(path: &Path) -> anyhow::Result<JwkSet> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read JWKS `{}`", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("Could not parse JWKS `{}`", path.display()))
+}
+
+/// Loads one password per line, lowercased so lookups are case-insensitive.
+fn load_password_blocklist(path: &Path) -> anyhow::Result<HashSet<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read password blocklist `{}`", path.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_lowercase)
+        .collect())
+}
+
+fn env_or<T>(var: &dyn Fn(&str) -> Option<String>, key: &str, default: T) -> anyhow::Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match var(key) {
+        Some(value) => value
+            .parse()
+            .map_err(|err| anyhow!("Invalid value for `{}`: `{}` ({})", key, value, err)),
+        None => Ok(default),
+    }
+}
+
+fn env_opt<T>(var: &dyn Fn(&str) -> Option<String>, key: &str) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match var(key) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|err| anyhow!("Invalid value for `{}`: `{}` ({})", key, value, err)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+
+    #[test]
+    fn reports_inconsistent_settings() {
+        let config = testing::config(&[
+            ("TOKEN_SIGNING_SECRET", "short"),
+            ("OIDC_ISSUER", "https://sso.example.org"),
+        ]);
+
+        let problems = config.problems();
+        assert!(problems.contains(&"`TOKEN_SIGNING_SECRET` is shorter than 32 bytes".to_string()));
+        assert!(problems.contains(
+            &"`OIDC_ISSUER` is set but `OIDC_AUDIENCE` or `OIDC_JWKS_PATH` is missing, so SSO is disabled"
+                .to_string()
+        ));
+    }
+
+    #[test]
+    fn keeps_warnings_apart_from_problems() {
+        let config = testing::config(&[("TOKEN_SIGNING_SECRET", ""), ("S3_BUCKET", "diaries")]);
+
+        assert_eq!(
+            config.warnings(),
+            vec![
+                "`TOKEN_SIGNING_SECRET` is not set, issued tokens will not survive a restart"
+                    .to_string(),
+                "`MAIL_TRANSPORT` is `log`, emails are written to the log instead of being sent"
+                    .to_string(),
+                "`S3_*` settings are ignored unless `DIARY_STORE=s3`".to_string(),
+            ]
+        );
+        assert!(!config
+            .problems()
+            .iter()
+            .any(|problem| problem.contains("TOKEN_SIGNING_SECRET") || problem.contains("S3_")));
+    }
+
+    #[test]
+    fn warns_that_access_tokens_are_not_bound() {
+        let config = testing::config(&[("AUTH_MODE", "jwt"), ("SESSION_BIND", "ip")]);
+
+        assert!(config.warnings().contains(
+            &"`SESSION_BIND` has no effect on the access tokens issued with `AUTH_MODE=jwt`"
+                .to_string()
+        ));
+        assert!(!testing::config(&[("SESSION_BIND", "ip")])
+            .warnings()
+            .iter()
+            .any(|warning| warning.contains("SESSION_BIND")));
+    }
+
+    #[test]
+    fn logging_mail_is_not_a_problem() {
+        let config = testing::config(&[("MAIL_TRANSPORT", "log")]);
+
+        assert!(config.problems().is_empty());
+    }
+
+    #[test]
+    fn requires_sendmail_for_the_sendmail_transport() {
+        let config = testing::config(&[
+            ("MAIL_TRANSPORT", "sendmail"),
+            ("SENDMAIL_PATH", "/nonexistent/sendmail"),
+        ]);
+
+        assert_eq!(
+            config.problems(),
+            vec!["`SENDMAIL_PATH` `/nonexistent/sendmail` does not exist".to_string()]
+        );
+        assert!(!config
+            .warnings()
+            .iter()
+            .any(|warning| warning.contains("MAIL_TRANSPORT")));
+    }
+}
@@ -0,0 +1,457 @@
+use crate::config::Config;
+
+use sqlx::pool::PoolConnection;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::{ConnectOptions, Connection, PgPool, Postgres};
+use std::future::Future;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+const BASE_BACKOFF_MS: u64 = 50;
+const MAX_STARTUP_BACKOFF_SECS: u64 = 30;
+const MIN_POOL_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Connects the pool, logging statements slower than `DB_SLOW_QUERY_WARN_MS` as warnings.
+/// While the database is unreachable, retries up to `DB_STARTUP_RETRIES` times, doubling
+/// the delay from `DB_STARTUP_RETRY_DELAY_MS` after each attempt.
+pub async fn connect(config: &Config) -> Result<PgPool, sqlx::Error> {
+    let mut options = PgConnectOptions::from_str(&config.database_url)?;
+    options.log_slow_statements(
+        log::LevelFilter::Warn,
+        Duration::from_millis(config.db_slow_query_warn_ms),
+    );
+
+    // probe with single connections, the pool itself would keep retrying a refused
+    // connection until its acquire timeout on every attempt
+    let mut delay = Duration::from_millis(config.db_startup_retry_delay_ms);
+    let mut attempt = 0;
+    loop {
+        match options.connect().await {
+            Ok(probe) => {
+                probe.close().await.ok();
+                break;
+            }
+            Err(err) if attempt < config.db_startup_retries && is_retryable(&err) => {
+                attempt += 1;
+                log::warn!(
+                    "Database unreachable ({}), retrying in {}ms (attempt {}/{})",
+                    err,
+                    delay.as_millis(),
+                    attempt,
+                    config.db_startup_retries
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(Duration::from_secs(MAX_STARTUP_BACKOFF_SECS));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    PgPoolOptions::new()
+        .max_connections(config.db_max_connections)
+        .connect_with(options)
+        .await
+}
+
+/// Takes a connection from the pool, warning when waiting for it took longer than
+/// `DB_ACQUIRE_WARN_MS`, which means requests are queueing for a connection.
+pub async fn acquire(
+    pool: &PgPool,
+    config: &Config,
+) -> Result<PoolConnection<Postgres>, sqlx::Error> {
+    let in_use = pool.size().saturating_sub(pool.num_idle() as u32);
+    let started = Instant::now();
+    let conn = pool.acquire().await;
+    let waited = started.elapsed();
+    if waited >= Duration::from_millis(config.db_acquire_warn_ms) {
+        log::warn!(
+            "Waited {}ms for a database connection, {}/{} were in use",
+            waited.as_millis(),
+            in_use,
+            config.db_max_connections
+        );
+    }
+    conn
+}
+
+/// Warns when every pooled connection has stayed in use for longer than `DB_ACQUIRE_WARN_MS`.
+/// This supplements [`acquire`], catching saturation on paths that query the pool directly.
+/// Stops with the pool.
+pub fn watch_pool(pool: PgPool, config: &Config) -> JoinHandle<()> {
+    let threshold = Duration::from_millis(config.db_acquire_warn_ms);
+    let max_connections = config.db_max_connections;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval((threshold / 4).max(MIN_POOL_CHECK_INTERVAL));
+        let mut saturated_since: Option<Instant> = None;
+        let mut warned = false;
+        while !pool.is_closed() {
+            interval.tick().await;
+            let in_use = pool.size().saturating_sub(pool.num_idle() as u32);
+            if in_use < max_connections {
+                saturated_since = None;
+                warned = false;
+                continue;
+            }
+            let since = *saturated_since.get_or_insert_with(Instant::now);
+            if !warned && since.elapsed() >= threshold {
+                log::warn!(
+                    "All {}/{} database connections have been in use for {}ms, queries are waiting for a connection",
+                    in_use,
+                    max_connections,
+                    since.elapsed().as_millis()
+                );
+                warned = true;
+            }
+        }
+    })
+}
+
+/// Runs `op`, retrying it up to `max_retries` times with exponential backoff
+/// when it fails with a transient database error. Other errors are returned immediately.
+pub async fn with_retry<T, F, Fut>(max_retries: u32, mut op: F) -> Result<T, sqlx::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Err(err) if attempt < max_retries && is_retryable(&err) => {
+                let backoff = Duration::from_millis(BASE_BACKOFF_MS << attempt.min(10));
+                log::warn!(
+                    "Retryable database error (attempt {}/{}): {}",
+                    attempt + 1,
+                    max_retries,
+                    err
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+pub fn is_retryable(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        sqlx::Error::Database(db) => matches!(
+            db.code().as_deref(),
+            // serialization_failure, deadlock_detected, cannot_connect_now,
+            // connection_exception family
+            Some("40001")
+                | Some("40P01")
+                | Some("57P03")
+                | Some("08000")
+                | Some("08003")
+                | Some("08006")
+        ),
+        _ => false,
+    }
+}
+
+pub fn is_unique_violation(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(db) => db.code().as_deref() == Some("23505"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn transient() -> sqlx::Error {
+        sqlx::Error::Io(std::io::Error::from(std::io::ErrorKind::ConnectionReset))
+    }
+
+    #[test]
+    fn classifies_transient_errors_as_retryable() {
+        assert!(is_retryable(&transient()));
+        assert!(is_retryable(&sqlx::Error::PoolTimedOut));
+        assert!(!is_retryable(&sqlx::Error::RowNotFound));
+        assert!(!is_retryable(&sqlx::Error::ColumnNotFound("uuid".into())));
+    }
+
+    #[tokio::test]
+    async fn warns_about_slow_acquires() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        testing::capture_logs();
+        let config = testing::config(&[("DB_ACQUIRE_WARN_MS", "50"), ("DB_MAX_CONNECTIONS", "5")]);
+        let mut held = Vec::new();
+        for _ in 0..5 {
+            held.push(pool.acquire().await.unwrap());
+        }
+        let release = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(held);
+        });
+
+        let conn = acquire(&pool, &config).await;
+
+        assert!(conn.is_ok());
+        assert!(testing::logged(
+            "for a database connection, 5/5 were in use"
+        ));
+        release.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn retries_a_flaky_operation_until_it_succeeds() {
+        let calls = AtomicU32::new(0);
+        let result = with_retry(3, || async {
+            if calls.fetch_add(1, Ordering::SeqCst) < 2 {
+                Err(transient())
+            } else {
+                Ok(42)
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = with_retry(2, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(transient())
+        })
+        .await;
+
+        assert!(matches!(result, Err(sqlx::Error::Io(_))));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn returns_non_retryable_errors_immediately() {
+        let calls = AtomicU32::new(0);
+        let result: Result<(), _> = with_retry(3, || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Err(sqlx::Error::RowNotFound)
+        })
+        .await;
+
+        assert!(matches!(result, Err(sqlx::Error::RowNotFound)));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_serialization_failures_from_postgres() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let config = testing::config(&[("DB_MAX_RETRIES", "2")]);
+
+        let calls = AtomicU32::new(0);
+        let result = with_retry(config.db_max_retries, || async {
+            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                sqlx::query(
+                    "DO $$ BEGIN RAISE EXCEPTION 'conflict' USING ERRCODE = 'serialization_failure'; END $$",
+                )
+                .execute(&pool)
+                .await?;
+            }
+            sqlx::query_scalar::<_, i32>("SELECT 1").fetch_one(&pool).await
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    fn test_database_config(vars: &[(&str, &str)]) -> Option<Config> {
+        let url = std::env::var("TEST_DATABASE_URL").ok()?;
+        let mut vars = vars.to_vec();
+        vars.push(("POSTGRES_DATABASE", &url));
+        Some(testing::config(&vars))
+    }
+
+    #[tokio::test]
+    async fn logs_slow_queries() {
+        let Some(config) = test_database_config(&[("DB_SLOW_QUERY_WARN_MS", "20")]) else {
+            return;
+        };
+        testing::capture_logs();
+        let pool = connect(&config).await.unwrap();
+
+        let marker = format!("slow-{}", uuid::Uuid::new_v4().simple());
+        sqlx::query(&format!("SELECT pg_sleep(0.05), '{}'", marker))
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(testing::logged(&marker));
+    }
+
+    #[tokio::test]
+    async fn warns_when_the_pool_stays_saturated() {
+        let Some(config) =
+            test_database_config(&[("DB_MAX_CONNECTIONS", "1"), ("DB_ACQUIRE_WARN_MS", "40")])
+        else {
+            return;
+        };
+        testing::capture_logs();
+        let pool = connect(&config).await.unwrap();
+
+        let held = pool.acquire().await.unwrap();
+        let watcher = watch_pool(pool.clone(), &config);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        drop(held);
+        watcher.abort();
+
+        assert!(testing::logged(
+            "All 1/1 database connections have been in use"
+        ));
+    }
+
+    /// A local port nothing listens on until it is bound again.
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_an_unreachable_database_after_the_startup_retries() {
+        let url = format!("postgres://postgres@127.0.0.1:{}/opendiary", free_port());
+        let config = testing::config(&[
+            ("POSTGRES_DATABASE", &url),
+            ("DB_STARTUP_RETRIES", "2"),
+            ("DB_STARTUP_RETRY_DELAY_MS", "10"),
+        ]);
+
+        let started = Instant::now();
+        let result = connect(&config).await;
+
+        assert!(matches!(result, Err(sqlx::Error::Io(_))));
+        // two retries, waiting 10ms and then 20ms
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[tokio::test]
+    async fn waits_for_the_database_to_come_up() {
+        let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+            return;
+        };
+        // `scheme://[user@]host:port/...`, where the database really listens
+        let (scheme, rest) = url.split_once("://").unwrap();
+        let (userinfo, rest) = rest.rsplit_once('@').map_or(("", rest), |(u, r)| (u, r));
+        let (upstream, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let upstream = if upstream.contains(':') {
+            upstream.to_string()
+        } else {
+            format!("{}:5432", upstream)
+        };
+
+        // the database only becomes reachable through this port after a delay
+        let port = free_port();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+                .await
+                .unwrap();
+            loop {
+                let (mut inbound, _) = listener.accept().await.unwrap();
+                let upstream = upstream.clone();
+                tokio::spawn(async move {
+                    let mut outbound = tokio::net::TcpStream::connect(upstream).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                });
+            }
+        });
+
+        let at = if userinfo.is_empty() { "" } else { "@" };
+        let url = format!("{}://{}{}127.0.0.1:{}/{}", scheme, userinfo, at, port, path);
+        let config = testing::config(&[
+            ("POSTGRES_DATABASE", &url),
+            ("DB_STARTUP_RETRIES", "10"),
+            ("DB_STARTUP_RETRY_DELAY_MS", "50"),
+        ]);
+
+        let pool = connect(&config).await.unwrap();
+        let one: i32 = sqlx::query_scalar("SELECT 1")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(one, 1);
+    }
+
+    /// Everything about the `public` schema of `pool` that migrations have to get right,
+    /// one line per column, index and enum label.
+    async fn describe_schema(pool: &PgPool) -> Vec<String> {
+        let columns = sqlx::query_as::<_, (String, String, String, String, Option<String>)>(
+            "SELECT table_name::text, column_name::text, udt_name::text, is_nullable::text, \
+            column_default::text FROM information_schema.columns WHERE table_schema = 'public'",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap();
+        let indexes = sqlx::query_as::<_, (String, String)>(
+            "SELECT tablename::text, indexdef FROM pg_indexes WHERE schemaname = 'public'",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap();
+        let labels = sqlx::query_as::<_, (String, String)>(
+            "SELECT t.typname::text, string_agg(e.enumlabel::text, ',' ORDER BY e.enumsortorder) \
+            FROM pg_enum e JOIN pg_type t ON t.oid = e.enumtypid GROUP BY t.typname",
+        )
+        .fetch_all(pool)
+        .await
+        .unwrap();
+
+        let mut schema: Vec<String> = columns
+            .into_iter()
+            .map(|column| format!("column {:?}", column))
+            .chain(
+                indexes
+                    .into_iter()
+                    .map(|index| format!("index {:?}", index)),
+            )
+            .chain(
+                labels
+                    .into_iter()
+                    .map(|labels| format!("enum {:?}", labels)),
+            )
+            .collect();
+        schema.sort();
+        schema
+    }
+
+    #[tokio::test]
+    async fn migrations_build_the_full_schema() {
+        let Some(full) = testing::pool().await else {
+            return;
+        };
+        let migrated = testing::empty_pool().await.unwrap();
+        let mut migrations: Vec<_> =
+            std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+        migrations.sort();
+
+        for migration in &migrations {
+            let sql = std::fs::read_to_string(migration).unwrap();
+            sqlx::Executor::execute(&migrated, sql.as_str())
+                .await
+                .unwrap_or_else(|err| panic!("{} fails: {}", migration.display(), err));
+        }
+
+        assert_eq!(
+            describe_schema(&migrated).await,
+            describe_schema(&full).await
+        );
+    }
+}
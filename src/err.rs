@@ -3,30 +3,107 @@
 use crate::{IntoResponse, Uri};
 
 use axum::extract::rejection::JsonRejection;
-use axum::http::StatusCode;
+use axum::extract::{FromRequest, Path, RequestParts};
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderValue, StatusCode};
 use axum::response::Response;
-use axum::{BoxError, Json};
+use axum::{async_trait, BoxError, Json};
+use chrono::{DateTime, Utc};
 
+use serde::de::DeserializeOwned;
 use serde::Serialize;
 
 pub fn handle_json_error(error: JsonRejection) -> (StatusCode, Error) {
-    (
-        StatusCode::BAD_REQUEST,
-        Error::InvalidPayload {
-            message: format!("Invalid payload: {}", error),
-        },
-    )
+    let message = format!("Invalid payload: {}", error);
+    let status = error.into_response().status();
+    let error = match status {
+        StatusCode::PAYLOAD_TOO_LARGE => Error::PayloadTooLarge { message },
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => Error::UnsupportedMediaType { message },
+        _ => Error::InvalidPayload { message },
+    };
+    (status, error)
+}
+
+/// Drop-in replacement for [`Json`] that reports rejections in the standard error envelope.
+#[derive(Debug, Clone)]
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for JsonBody<T>
+where
+    T: DeserializeOwned,
+    B: axum::body::HttpBody + Send,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = (StatusCode, Maybe<()>);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req).await {
+            Ok(Json(value)) => Ok(JsonBody(value)),
+            Err(rejection) => {
+                let (status, error) = handle_json_error(rejection);
+                Err((status, Nothing(error)))
+            }
+        }
+    }
 }
 
-pub async fn handler404(path: Uri) -> (StatusCode, Json<Error>) {
+pub async fn handler404(path: Uri) -> (StatusCode, Maybe<()>) {
     (
         StatusCode::NOT_FOUND,
-        Json(Error::NotFound {
+        Nothing(Error::NotFound {
             message: format!("Invalid path: {}", path),
         }),
     )
 }
 
+/// Longest raw path segment accepted before any handler work is done.
+pub const MAX_PATH_SEGMENT_LEN: usize = 256;
+
+/// Drop-in replacement for [`Path`] that rejects overlong segments up front
+/// and reports rejections in the standard error envelope.
+#[derive(Debug, Clone)]
+pub struct BoundedPath<T>(pub T);
+
+#[async_trait]
+impl<T, B> FromRequest<B> for BoundedPath<T>
+where
+    T: DeserializeOwned + Send,
+    B: Send,
+{
+    type Rejection = (StatusCode, Maybe<()>);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        if req
+            .uri()
+            .path()
+            .split('/')
+            .any(|segment| segment.len() > MAX_PATH_SEGMENT_LEN)
+        {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                Nothing(Error::InvalidPayload {
+                    message: format!(
+                        "Path segments must be at most {} bytes long",
+                        MAX_PATH_SEGMENT_LEN
+                    ),
+                }),
+            ));
+        }
+
+        match Path::<T>::from_request(req).await {
+            Ok(Path(value)) => Ok(BoundedPath(value)),
+            Err(rejection) => Err((
+                StatusCode::BAD_REQUEST,
+                Nothing(Error::InvalidPayload {
+                    message: format!("Invalid path: {}", rejection),
+                }),
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum Maybe<T> {
@@ -79,23 +156,103 @@ impl<V: Serialize> Success<V> {
 #[derive(Debug, Clone, Serialize)]
 #[serde(tag = "error")]
 pub enum Error {
-    NotFound { message: String },
-    InternalError { kind: &'static str, message: String },
-    Unknown { message: String },
-    MissingCredentials { message: String },
-    UserAlreadyExists { message: String },
-    UserDoesNotExist { message: String },
-    AuthenticationFailure { message: String },
-    InvalidPayload { message: String },
+    NotFound {
+        message: String,
+    },
+    InternalError {
+        kind: &'static str,
+        message: String,
+    },
+    Unknown {
+        message: String,
+    },
+    MissingCredentials {
+        message: String,
+    },
+    UserAlreadyExists {
+        message: String,
+    },
+    UserDoesNotExist {
+        message: String,
+    },
+    AuthenticationFailure {
+        message: String,
+    },
+    InvalidPayload {
+        message: String,
+    },
+    PayloadTooLarge {
+        message: String,
+    },
+    UnsupportedMediaType {
+        message: String,
+    },
+    ServiceUnavailable {
+        message: String,
+    },
+    WeakPassword {
+        message: String,
+    },
+    RateLimited {
+        message: String,
+        retry_after: u64,
+    },
+    HttpsRequired {
+        message: String,
+    },
+    EmailDomainNotAllowed {
+        message: String,
+    },
+    InvalidIdentityToken {
+        message: String,
+    },
+    IdentityAlreadyLinked {
+        message: String,
+    },
+    EmailNotVerified {
+        message: String,
+    },
+    TwoFactorRequired {
+        message: String,
+    },
+    Forbidden {
+        message: String,
+    },
+    AccountLocked {
+        message: String,
+        locked_until: DateTime<Utc>,
+    },
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        Json::into_response(Json(self))
+        let retry_after = match &self {
+            Error::RateLimited { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        };
+        let mut response = (self.status_code(), Nothing::<()>(self)).into_response();
+        if let Some(seconds) = retry_after {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
     }
 }
 
 impl Error {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::PayloadTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::UnsupportedMediaType { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::ServiceUnavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Error::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Error::HttpsRequired { .. } | Error::Forbidden { .. } => StatusCode::FORBIDDEN,
+            // everything else is reported in-band, clients only look at `success`
+            _ => StatusCode::OK,
+        }
+    }
+
     pub fn unknown<S: Into<String>>(msg: S) -> Error {
         Error::Unknown {
             message: msg.into(),
@@ -148,7 +305,15 @@ impl From<BoxError> for Error {
 }
 
 impl From<sqlx::Error> for Error {
+    /// Failures that retrying may fix are reported as `ServiceUnavailable`, so clients know to
+    /// come back later.
     fn from(err: sqlx::Error) -> Self {
+        if crate::db::is_retryable(&err) {
+            log::warn!("Database unavailable: {}", err);
+            return Self::ServiceUnavailable {
+                message: "The database is temporarily unavailable, try again later".to_string(),
+            };
+        }
         Self::InternalError {
             kind: "DatabaseError",
             message: err.to_string(),
@@ -156,6 +321,15 @@ impl From<sqlx::Error> for Error {
     }
 }
 
+impl From<jsonwebtoken::errors::Error> for Error {
+    fn from(err: jsonwebtoken::errors::Error) -> Self {
+        Self::InternalError {
+            kind: "TokenError",
+            message: err.to_string(),
+        }
+    }
+}
+
 impl From<pbkdf2::password_hash::Error> for Error {
     fn from(err: pbkdf2::password_hash::Error) -> Self {
         Self::InternalError {
@@ -164,3 +338,134 @@ impl From<pbkdf2::password_hash::Error> for Error {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    use axum::body::Body;
+    use axum::http::Request;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn handler_errors_use_the_envelope() {
+        let response = Error::InvalidPayload {
+            message: "bad".to_string(),
+        }
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            String::from_utf8(testing::body_bytes(response).await).unwrap(),
+            r#"{"success":false,"error":"InvalidPayload","message":"bad"}"#
+        );
+
+        let response = Error::PayloadTooLarge {
+            message: "big".to_string(),
+        }
+        .into_response();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            String::from_utf8(testing::body_bytes(response).await).unwrap(),
+            r#"{"success":false,"error":"PayloadTooLarge","message":"big"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn envelopes_oversized_bodies_as_413() {
+        let config = testing::config(&[("MAX_BODY_BYTES", "64")]);
+        let app = testing::app(config, testing::lazy_pool()).await;
+
+        let request = testing::post(
+            "/student/normalize_username",
+            json!({ "username": "a".repeat(128) }),
+        );
+        let response = testing::send(&app, request).await;
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = testing::body_json(response).await;
+        assert_eq!(body["success"], false);
+        assert_eq!(body["error"], "PayloadTooLarge");
+    }
+
+    #[tokio::test]
+    async fn envelopes_non_json_bodies_as_415() {
+        let app = testing::app(testing::config(&[]), testing::lazy_pool()).await;
+
+        let request = Request::post("/student/normalize_username")
+            .header("content-type", "text/plain")
+            .body(Body::from(r#"{"username": "student"}"#))
+            .unwrap();
+        let response = testing::send(&app, request).await;
+
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let body = testing::body_json(response).await;
+        assert_eq!(body["success"], false);
+        assert_eq!(body["error"], "UnsupportedMediaType");
+    }
+
+    #[tokio::test]
+    async fn envelopes_malformed_bodies() {
+        let app = testing::app(testing::config(&[]), testing::lazy_pool()).await;
+
+        let response = testing::send(
+            &app,
+            testing::post("/student/normalize_username", json!({})),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = testing::body_json(response).await;
+        assert_eq!(body["success"], false);
+        assert_eq!(body["error"], "InvalidPayload");
+    }
+
+    #[tokio::test]
+    async fn rejects_overlong_path_segments_before_the_database() {
+        // the lazy pool points at a database that does not exist
+        let app = testing::app(testing::config(&[]), testing::lazy_pool()).await;
+
+        for uri in [
+            format!(
+                "/student/get_id/{}",
+                "a".repeat(super::MAX_PATH_SEGMENT_LEN + 1)
+            ),
+            format!("/teacher/profile/{}", "a".repeat(4096)),
+        ] {
+            let response = testing::send(&app, testing::get(&uri)).await;
+
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let body = testing::body_json(response).await;
+            assert_eq!(body["error"], "InvalidPayload");
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_an_unreachable_database_as_503() {
+        // nothing listens on port 1
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(std::time::Duration::from_millis(200))
+            .connect_lazy("postgres://127.0.0.1:1/opendiary")
+            .unwrap();
+        let app = testing::app(testing::config(&[]), pool).await;
+
+        let response = testing::send(&app, testing::get("/student/get_id/student")).await;
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            testing::body_json(response).await["error"],
+            "ServiceUnavailable"
+        );
+    }
+
+    #[test]
+    fn keeps_other_database_errors_internal() {
+        assert!(matches!(
+            super::Error::from(sqlx::Error::RowNotFound),
+            super::Error::InternalError {
+                kind: "DatabaseError",
+                ..
+            }
+        ));
+    }
+}
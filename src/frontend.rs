@@ -0,0 +1,98 @@
+use crate::config::Config;
+use crate::{Error, IntoResponse};
+
+use axum::body::{boxed, Body};
+use axum::http::Request;
+use axum::response::Response;
+use axum::Extension;
+use std::sync::Arc;
+use tower::ServiceExt;
+use tower_http::services::{ServeDir, ServeFile};
+
+/// Path prefixes owned by the API, never handed over to the frontend bundle.
+const API_PREFIXES: &[&str] = &["/student/", "/session/", "/teacher/", "/parent/", "/admin/"];
+
+/// Fallback for every unrouted path. Serves the frontend bundle from `STATIC_DIR`,
+/// answering unknown frontend routes with `index.html` so SPA deep links work.
+/// Unmatched API paths, or any path when no bundle is configured, still get the JSON 404.
+pub async fn serve_frontend(
+    Extension(config): Extension<Arc<Config>>,
+    request: Request<Body>,
+) -> Response {
+    let path = request.uri().path();
+    let static_dir = match &config.static_dir {
+        Some(dir) if !API_PREFIXES.iter().any(|prefix| path.starts_with(prefix)) => dir,
+        _ => {
+            let uri = request.uri().clone();
+            return crate::err::handler404(uri).await.into_response();
+        }
+    };
+
+    let service = ServeDir::new(static_dir).fallback(ServeFile::new(static_dir.join("index.html")));
+    match service.oneshot(request).await {
+        Ok(response) => response.map(boxed),
+        Err(err) => Error::from(err).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+
+    use crate::testing::get;
+
+    use axum::http::StatusCode;
+
+    async fn app_with_bundle() -> axum::Router {
+        let dir = testing::temp_dir();
+        std::fs::write(dir.join("index.html"), "<html>spa</html>").unwrap();
+        std::fs::write(dir.join("app.js"), "console.log('app')").unwrap();
+        let config = testing::config(&[("STATIC_DIR", dir.to_str().unwrap())]);
+        testing::app(config, testing::lazy_pool()).await
+    }
+
+    #[tokio::test]
+    async fn serves_files_from_the_bundle() {
+        let app = app_with_bundle().await;
+
+        let response = testing::send(&app, get("/app.js")).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(testing::body_bytes(response).await, b"console.log('app')");
+    }
+
+    #[tokio::test]
+    async fn answers_deep_links_with_the_index() {
+        let app = app_with_bundle().await;
+
+        let response = testing::send(&app, get("/diary/2024/week/3")).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(testing::body_bytes(response).await, b"<html>spa</html>");
+    }
+
+    #[tokio::test]
+    async fn keeps_the_json_404_for_unknown_api_paths() {
+        let app = app_with_bundle().await;
+
+        let response = testing::send(&app, get("/student/no_such_endpoint")).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = testing::body_json(response).await;
+        assert_eq!(body["success"], false);
+        assert_eq!(body["error"], "NotFound");
+    }
+
+    #[tokio::test]
+    async fn envelopes_404s_without_a_bundle() {
+        let app = testing::app(testing::config(&[]), testing::lazy_pool()).await;
+
+        let response = testing::send(&app, get("/index.html")).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = testing::body_json(response).await;
+        assert_eq!(body["success"], false);
+        assert_eq!(body["error"], "NotFound");
+        assert_eq!(body["message"], "Invalid path: /index.html");
+    }
+}
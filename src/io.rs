@@ -1,31 +1,368 @@
 use anyhow::bail;
-use std::path::PathBuf;
+use async_trait::async_trait;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 
-use tokio::fs::{create_dir_all, File};
-use tokio::io::{AsyncReadExt, BufReader};
+use crate::config::{Config, DiaryStoreKind};
 
-pub async fn prepare_io() {
-    let diary_dir = PathBuf::from("diary");
-    create_dir_all(diary_dir).await.unwrap();
+use tokio::fs::{create_dir_all, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+
+/// Storage for diary files. Paths are `/`-separated and relative to the store root.
+#[async_trait]
+pub trait DiaryStore: Send + Sync {
+    /// Stores a new file. Fails if a file already exists at `path`, files are never overwritten.
+    async fn write(&self, path: &str, bytes: &[u8]) -> anyhow::Result<()>;
+    async fn read(&self, path: &str) -> anyhow::Result<Vec<u8>>;
+    async fn delete(&self, path: &str) -> anyhow::Result<()>;
+    /// Lists every file below `prefix`, as paths relative to the store root.
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>>;
+}
+
+pub async fn open_store(config: &Config) -> anyhow::Result<Arc<dyn DiaryStore>> {
+    match config.diary_store {
+        DiaryStoreKind::Local => Ok(Arc::new(LocalStore::new(&config.diary_dir).await?)),
+        #[cfg(feature = "s3")]
+        DiaryStoreKind::S3 => Ok(Arc::new(s3_store::S3Store::from_config(config)?)),
+        #[cfg(not(feature = "s3"))]
+        DiaryStoreKind::S3 => bail!("`DIARY_STORE=s3` requires building with the `s3` feature"),
+    }
+}
+
+/// Rejects empty paths and any with parts other than plain names, like `..` or a leading `/`.
+pub fn validate_path(path: &str) -> anyhow::Result<()> {
+    let escapes = Path::new(path)
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_)));
+    if path.is_empty() || escapes {
+        bail!("Invalid diary path `{}`", path)
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub async fn new<P: Into<PathBuf>>(root: P) -> anyhow::Result<Self> {
+        let root = root.into();
+        create_dir_all(&root).await?;
+        Ok(Self { root })
+    }
+
+    fn resolve(&self, path: &str) -> anyhow::Result<PathBuf> {
+        validate_path(path)?;
+        Ok(self.root.join(path))
+    }
 }
 
-pub async fn create_io_file<S: Into<String>>(path: S) -> anyhow::Result<File> {
-    let pathbuf = PathBuf::from(path.into());
-    create_dir_all(pathbuf.parent().unwrap()).await?;
-    if pathbuf.exists() {
-        bail!("File already exists!")
+#[async_trait]
+impl DiaryStore for LocalStore {
+    async fn write(&self, path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+        let pathbuf = self.resolve(path)?;
+        if let Some(parent) = pathbuf.parent() {
+            create_dir_all(parent).await?;
+        }
+        let mut file = match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(pathbuf)
+            .await
+        {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                bail!("File already exists!")
+            }
+            Err(err) => return Err(err.into()),
+        };
+        file.write_all(bytes).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    async fn read(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+        let buf = self.resolve(path)?;
+        if !buf.exists() {
+            bail!("Tried to read nonexistent file!")
+        }
+        let mut bytes = Vec::new();
+        BufReader::new(File::open(buf).await?)
+            .read_to_end(&mut bytes)
+            .await?;
+        Ok(bytes)
+    }
+
+    async fn delete(&self, path: &str) -> anyhow::Result<()> {
+        let buf = self.resolve(path)?;
+        if !buf.exists() {
+            bail!("Tried to delete nonexistent file!")
+        }
+        tokio::fs::remove_file(buf).await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+        let start = if prefix.is_empty() {
+            self.root.clone()
+        } else {
+            self.resolve(prefix)?
+        };
+
+        let mut files = Vec::new();
+        let mut pending = vec![start];
+        while let Some(dir) = pending.pop() {
+            if !dir.is_dir() {
+                continue;
+            }
+            let mut entries = tokio::fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                if entry.file_type().await?.is_dir() {
+                    pending.push(path);
+                } else if let Ok(relative) = path.strip_prefix(&self.root) {
+                    let parts: Vec<_> = relative
+                        .components()
+                        .map(|c| c.as_os_str().to_string_lossy())
+                        .collect();
+                    files.push(parts.join("/"));
+                }
+            }
+        }
+        files.sort();
+        Ok(files)
     }
-    return File::create(pathbuf).await.map_err(anyhow::Error::from);
 }
 
-pub async fn read_io_file<S: Into<String>>(path: S) -> anyhow::Result<Vec<u8>> {
-    let buf = PathBuf::from(path.into());
-    if !buf.exists() {
-        bail!("Tried to read nonexistent file!")
+#[cfg(feature = "s3")]
+mod s3_store {
+    use super::{validate_path, DiaryStore};
+    use crate::config::Config;
+
+    use ::s3::creds::Credentials;
+    use ::s3::{Bucket, Region};
+    use anyhow::{bail, Context};
+    use async_trait::async_trait;
+
+    pub struct S3Store {
+        bucket: Bucket,
+    }
+
+    impl S3Store {
+        pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+            let name = config
+                .s3_bucket
+                .as_deref()
+                .context("`S3_BUCKET` must be set when `DIARY_STORE=s3`")?;
+            let region = match &config.s3_endpoint {
+                Some(endpoint) => Region::Custom {
+                    region: config.s3_region.clone(),
+                    endpoint: endpoint.clone(),
+                },
+                None => config.s3_region.parse()?,
+            };
+            let bucket = Bucket::new(name, region, Credentials::from_env()?)?.with_path_style();
+            Ok(Self::new(bucket))
+        }
+
+        pub fn new(bucket: Bucket) -> Self {
+            Self { bucket }
+        }
+    }
+
+    fn ensure_ok(status: u16, path: &str) -> anyhow::Result<()> {
+        if !(200..300).contains(&status) {
+            bail!("S3 request for `{}` failed with status {}", path, status)
+        }
+        Ok(())
+    }
+
+    #[async_trait]
+    impl DiaryStore for S3Store {
+        async fn write(&self, path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+            validate_path(path)?;
+            // S3 has no create-only put here, so a concurrent writer can still slip in
+            // between the check and the upload
+            let (_, status) = self.bucket.head_object(path).await?;
+            if status != 404 {
+                ensure_ok(status, path)?;
+                bail!("File already exists!")
+            }
+            let response = self.bucket.put_object(path, bytes).await?;
+            ensure_ok(response.status_code(), path)
+        }
+
+        async fn read(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+            validate_path(path)?;
+            let response = self.bucket.get_object(path).await?;
+            ensure_ok(response.status_code(), path)?;
+            Ok(response.to_vec())
+        }
+
+        async fn delete(&self, path: &str) -> anyhow::Result<()> {
+            validate_path(path)?;
+            let response = self.bucket.delete_object(path).await?;
+            ensure_ok(response.status_code(), path)
+        }
+
+        async fn list(&self, prefix: &str) -> anyhow::Result<Vec<String>> {
+            let mut files: Vec<String> = self
+                .bucket
+                .list(prefix.to_string(), None)
+                .await?
+                .into_iter()
+                .flat_map(|page| page.contents)
+                .map(|object| object.key)
+                .collect();
+            files.sort();
+            Ok(files)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    /// Runs the same scenario against any store, so every backend behaves alike.
+    async fn exercise(store: &dyn DiaryStore) {
+        store.write("student/2024/01.md", b"first").await.unwrap();
+        store.write("student/2024/02.md", b"second").await.unwrap();
+        store.write("other/notes.md", b"other").await.unwrap();
+
+        assert_eq!(store.read("student/2024/01.md").await.unwrap(), b"first");
+        assert_eq!(
+            store.list("student").await.unwrap(),
+            vec!["student/2024/01.md", "student/2024/02.md"]
+        );
+
+        // existing files are never overwritten
+        assert!(store.write("student/2024/01.md", b"again").await.is_err());
+        assert_eq!(store.read("student/2024/01.md").await.unwrap(), b"first");
+
+        store.delete("student/2024/01.md").await.unwrap();
+        assert!(store.read("student/2024/01.md").await.is_err());
+        assert_eq!(
+            store.list("student").await.unwrap(),
+            vec!["student/2024/02.md"]
+        );
+    }
+
+    #[tokio::test]
+    async fn local_store_reads_writes_lists_and_deletes() {
+        let store = LocalStore::new(testing::temp_dir()).await.unwrap();
+        exercise(&store).await;
+    }
+
+    #[tokio::test]
+    async fn local_store_rejects_paths_outside_its_root() {
+        let store = LocalStore::new(testing::temp_dir()).await.unwrap();
+
+        for path in ["", "../escape.md", "/etc/passwd", "student/../../escape.md"] {
+            assert!(store.write(path, b"nope").await.is_err(), "{}", path);
+            assert!(store.read(path).await.is_err(), "{}", path);
+        }
+    }
+
+    #[cfg(feature = "s3")]
+    mod s3 {
+        use super::exercise;
+        use crate::io::s3_store::S3Store;
+
+        use ::s3::creds::Credentials;
+        use ::s3::{Bucket, Region};
+        use axum::body::Body;
+        use axum::handler::Handler;
+        use axum::http::{Method, Request, StatusCode};
+        use axum::response::{IntoResponse, Response};
+        use axum::{Extension, Router};
+        use std::collections::BTreeMap;
+        use std::sync::{Arc, Mutex};
+
+        type Objects = Arc<Mutex<BTreeMap<String, Vec<u8>>>>;
+
+        /// Just enough of the S3 API for `S3Store`, keeping objects in memory.
+        async fn mock_s3(
+            Extension(objects): Extension<Objects>,
+            request: Request<Body>,
+        ) -> Response {
+            let method = request.method().clone();
+            let uri = request.uri().clone();
+            let key = uri
+                .path()
+                .trim_start_matches("/diary")
+                .trim_start_matches('/');
+            let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+            let mut objects = objects.lock().unwrap();
+
+            if key.is_empty() {
+                let prefix = uri
+                    .query()
+                    .unwrap_or_default()
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("prefix="))
+                    .unwrap_or_default();
+                let contents: String = objects
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix))
+                    .map(|(key, bytes)| {
+                        format!(
+                            "<Contents><Key>{}</Key><LastModified>2024-01-01T00:00:00.000Z</LastModified>\
+                            <ETag>\"etag\"</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                            key,
+                            bytes.len()
+                        )
+                    })
+                    .collect();
+                return format!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?><ListBucketResult><Name>diary</Name>\
+                    <Prefix>{}</Prefix><IsTruncated>false</IsTruncated>{}</ListBucketResult>",
+                    prefix, contents
+                )
+                .into_response();
+            }
+
+            match method {
+                Method::PUT => {
+                    objects.insert(key.to_string(), body.to_vec());
+                    StatusCode::OK.into_response()
+                }
+                Method::HEAD | Method::GET => match objects.get(key) {
+                    Some(bytes) if method == Method::GET => bytes.clone().into_response(),
+                    Some(_) => StatusCode::OK.into_response(),
+                    None => StatusCode::NOT_FOUND.into_response(),
+                },
+                Method::DELETE => {
+                    objects.remove(key);
+                    StatusCode::NO_CONTENT.into_response()
+                }
+                _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+            }
+        }
+
+        #[tokio::test]
+        async fn s3_store_reads_writes_lists_and_deletes() {
+            let app = Router::new()
+                .fallback(mock_s3.into_service())
+                .layer(Extension(Objects::default()));
+            let server =
+                axum::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(app.into_make_service());
+            let endpoint = format!("http://{}", server.local_addr());
+            tokio::spawn(server);
+
+            let region = Region::Custom {
+                region: "us-east-1".to_string(),
+                endpoint,
+            };
+            let credentials =
+                Credentials::new(Some("key"), Some("secret"), None, None, None).unwrap();
+            let bucket = Bucket::new("diary", region, credentials)
+                .unwrap()
+                .with_path_style();
+
+            exercise(&S3Store::new(bucket)).await;
+        }
     }
-    let mut bytes = Vec::new();
-    BufReader::new(File::open(buf).await?)
-        .read_to_end(&mut bytes)
-        .await?;
-    Ok(bytes)
 }
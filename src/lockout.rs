@@ -0,0 +1,287 @@
+use crate::auth::ClientInfo;
+use crate::config::Config;
+use crate::Error;
+
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Failures are counted per client address, so that guessing from one client does not
+/// lock the account for everyone else. Clients without a known address share one count.
+fn client_key(client: &ClientInfo) -> String {
+    client.ip.map(|ip| ip.to_string()).unwrap_or_default()
+}
+
+/// Rejects logins from a client locked out of an account by [`record_login_failure`]
+/// until the lock runs out.
+pub async fn check_lockout(
+    student_id: Uuid,
+    client: &ClientInfo,
+    pg: &PgPool,
+) -> Result<(), Error> {
+    let locked_until = sqlx::query_scalar::<_, Option<DateTime<Utc>>>(
+        "SELECT locked_until FROM login_failures WHERE belongs_to = $1 AND client = $2",
+    )
+    .bind(student_id)
+    .bind(client_key(client))
+    .fetch_optional(pg)
+    .await
+    .map_err(Error::from)?
+    .flatten();
+
+    match locked_until {
+        Some(locked_until) if locked_until > Utc::now() => Err(Error::AccountLocked {
+            message: "Account is locked after too many failed logins".to_string(),
+            locked_until,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Counts a failed login of a client, locking it out of the account for `LOCKOUT_DURATION_MINUTES`
+/// once `LOCKOUT_THRESHOLD` consecutive failures are reached.
+pub async fn record_login_failure(
+    student_id: Uuid,
+    client: &ClientInfo,
+    pg: &PgPool,
+    config: &Config,
+) -> Result<(), Error> {
+    if config.lockout_threshold == 0 {
+        return Ok(());
+    }
+
+    let client = client_key(client);
+    let failures = sqlx::query_scalar::<_, i32>(
+        "INSERT INTO login_failures (belongs_to, client, failures) VALUES ($1, $2, 1) \
+        ON CONFLICT (belongs_to, client) DO UPDATE SET failures = login_failures.failures + 1 \
+        RETURNING failures",
+    )
+    .bind(student_id)
+    .bind(&client)
+    .fetch_one(pg)
+    .await
+    .map_err(Error::from)?;
+
+    if failures as u32 >= config.lockout_threshold {
+        let locked_until = Utc::now() + Duration::minutes(config.lockout_duration_minutes);
+        sqlx::query(
+            "UPDATE login_failures SET failures = 0, locked_until = $3 \
+            WHERE belongs_to = $1 AND client = $2",
+        )
+        .bind(student_id)
+        .bind(&client)
+        .bind(locked_until)
+        .execute(pg)
+        .await
+        .map_err(Error::from)?;
+        log::warn!(
+            "Locked client `{}` out of account {} until {} after {} failed logins",
+            client,
+            student_id,
+            locked_until,
+            failures
+        );
+    }
+    Ok(())
+}
+
+/// Resets the failure count of a client after it logged in successfully.
+pub async fn clear_login_failures(
+    student_id: Uuid,
+    client: &ClientInfo,
+    pg: &PgPool,
+) -> Result<(), Error> {
+    sqlx::query("DELETE FROM login_failures WHERE belongs_to = $1 AND client = $2")
+        .bind(student_id)
+        .bind(client_key(client))
+        .execute(pg)
+        .await
+        .map_err(Error::from)?;
+    Ok(())
+}
+
+/// Lifts the locks and failure counts of every client of an account.
+pub async fn lift_lockouts(student_id: Uuid, pg: &PgPool) -> Result<bool, Error> {
+    let cleared = sqlx::query("DELETE FROM login_failures WHERE belongs_to = $1")
+        .bind(student_id)
+        .execute(pg)
+        .await
+        .map_err(Error::from)?;
+    Ok(cleared.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Role;
+    use crate::testing;
+
+    use serde_json::json;
+
+    async fn login(app: &axum::Router, student: Uuid, password: &str) -> serde_json::Value {
+        login_from(app, "127.0.0.1:40000", student, password).await
+    }
+
+    async fn login_from(
+        app: &axum::Router,
+        peer: &str,
+        student: Uuid,
+        password: &str,
+    ) -> serde_json::Value {
+        let request = testing::post(
+            "/session/login",
+            json!({ "uuid": student, "password": password }),
+        );
+        testing::body_json(testing::send_from(app, peer, request).await).await
+    }
+
+    /// The client [`testing::send`] requests come from.
+    fn local() -> ClientInfo {
+        ClientInfo {
+            ip: Some("127.0.0.1".parse().unwrap()),
+            user_agent: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn locks_after_consecutive_failures() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let app = testing::app(testing::config(&[("LOCKOUT_THRESHOLD", "3")]), pool.clone()).await;
+
+        // a success in between starts the count over
+        login(&app, student, "wrong").await;
+        login(&app, student, "wrong").await;
+        assert_eq!(login(&app, student, "password").await["success"], true);
+        for _ in 0..2 {
+            assert_eq!(
+                login(&app, student, "wrong").await["error"],
+                "AuthenticationFailure"
+            );
+        }
+        assert!(check_lockout(student, &local(), &pool).await.is_ok());
+
+        assert_eq!(
+            login(&app, student, "wrong").await["error"],
+            "AuthenticationFailure"
+        );
+        let body = login(&app, student, "password").await;
+        assert_eq!(body["error"], "AccountLocked");
+        assert!(body["locked_until"].is_string());
+    }
+
+    #[tokio::test]
+    async fn locks_out_only_the_guessing_client() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let app = testing::app(testing::config(&[("LOCKOUT_THRESHOLD", "3")]), pool.clone()).await;
+        let guesser = "203.0.113.9:40000";
+
+        for _ in 0..3 {
+            login_from(&app, guesser, student, "wrong").await;
+        }
+        assert_eq!(
+            login_from(&app, guesser, student, "password").await["error"],
+            "AccountLocked"
+        );
+
+        // the student logs in from elsewhere, which does not lift the guesser's lock
+        assert_eq!(
+            login_from(&app, "198.51.100.1:40000", student, "password").await["success"],
+            true
+        );
+        assert_eq!(
+            login_from(&app, guesser, student, "password").await["error"],
+            "AccountLocked"
+        );
+    }
+
+    #[tokio::test]
+    async fn unlocks_once_the_lock_runs_out() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let config = testing::config(&[("LOCKOUT_THRESHOLD", "1")]);
+
+        record_login_failure(student, &local(), &pool, &config)
+            .await
+            .unwrap();
+        assert!(matches!(
+            check_lockout(student, &local(), &pool).await,
+            Err(Error::AccountLocked { .. })
+        ));
+
+        sqlx::query("UPDATE login_failures SET locked_until = $2 WHERE belongs_to = $1")
+            .bind(student)
+            .bind(Utc::now() - Duration::seconds(1))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(check_lockout(student, &local(), &pool).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn never_locks_with_a_zero_threshold() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let config = testing::config(&[("LOCKOUT_THRESHOLD", "0")]);
+
+        for _ in 0..10 {
+            record_login_failure(student, &local(), &pool, &config)
+                .await
+                .unwrap();
+        }
+        assert!(check_lockout(student, &local(), &pool).await.is_ok());
+        assert!(!lift_lockouts(student, &pool).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn admins_can_unlock_accounts() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let admin = testing::insert_user(&pool, "admin", "password").await;
+        testing::set_role(&pool, admin, Role::Admin).await;
+        let admin_ssid =
+            testing::insert_session(&pool, admin, Utc::now() + Duration::hours(1)).await;
+        let student_ssid =
+            testing::insert_session(&pool, student, Utc::now() + Duration::hours(1)).await;
+        let config = testing::config(&[("LOCKOUT_THRESHOLD", "1")]);
+        record_login_failure(student, &local(), &pool, &config)
+            .await
+            .unwrap();
+        let app = testing::app(config, pool.clone()).await;
+        let unlock = |ssid: &str| {
+            testing::send(
+                &app,
+                testing::post(
+                    "/admin/unlock_account",
+                    json!({ "ssid": ssid, "uuid": student }),
+                ),
+            )
+        };
+
+        // students cannot lift their own lock
+        assert_eq!(
+            unlock(&student_ssid).await.status(),
+            axum::http::StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            login(&app, student, "password").await["error"],
+            "AccountLocked"
+        );
+
+        let body = testing::body_json(unlock(&admin_ssid).await).await;
+        assert_eq!(body["auth_result"], "Success");
+        assert_eq!(body["unlocked"], true);
+        assert_eq!(login(&app, student, "password").await["success"], true);
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::config::{Config, MailTransport};
+
+/// Delivery of plain-text emails.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> anyhow::Result<()>;
+}
+
+pub fn open_mailer(config: &Config) -> Arc<dyn Mailer> {
+    match config.mail_transport {
+        MailTransport::Log => Arc::new(LogMailer),
+        MailTransport::Sendmail => Arc::new(SendmailMailer {
+            path: config.sendmail_path.clone(),
+            from: config.mail_from.clone(),
+        }),
+    }
+}
+
+/// Rejects values that could smuggle extra headers into a message.
+fn validate_header(value: &str) -> anyhow::Result<()> {
+    if value.contains(['\r', '\n']) {
+        bail!("Mail header contains a line break")
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> anyhow::Result<()> {
+        validate_header(to)?;
+        log::info!("Mail to {}: {}\n{}", to, subject, body);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SendmailMailer {
+    path: PathBuf,
+    from: String,
+}
+
+#[async_trait]
+impl Mailer for SendmailMailer {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> anyhow::Result<()> {
+        validate_header(to)?;
+        validate_header(subject)?;
+        let message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+            self.from, to, subject, body
+        );
+
+        let mut child = Command::new(&self.path)
+            .args(["-i", "--", to])
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Could not run `{}`", self.path.display()))?;
+        let mut stdin = child.stdin.take().context("sendmail stdin is not piped")?;
+        stdin.write_all(message.as_bytes()).await?;
+        drop(stdin);
+
+        let status = child.wait().await?;
+        if !status.success() {
+            bail!("sendmail exited with {}", status)
+        }
+        Ok(())
+    }
+}
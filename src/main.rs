@@ -1,20 +1,50 @@
 pub mod auth;
+pub mod config;
+pub mod db;
 pub mod err;
+pub mod frontend;
 pub mod io;
+pub mod lockout;
+pub mod mail;
 pub mod models;
+pub mod msgpack;
+pub mod parent;
+pub mod ratelimit;
+pub mod reset;
+pub mod roles;
+pub mod security;
+pub mod sso;
+pub mod status;
+pub mod teacher;
+pub mod tokens;
+pub mod totp;
+pub mod verify;
+
+#[cfg(test)]
+mod testing;
 
 use axum::{response::IntoResponse, routing::get, routing::post, Extension, Json, Router};
 
+use crate::config::{Config, HttpsPolicy};
 use crate::err::{Error, Fine, Maybe, Nothing};
+use crate::io::DiaryStore;
+use crate::mail::Mailer;
+use crate::ratelimit::{RateLimitKey, RateLimitLayer, SessionBudget};
 
-use axum::http::Uri;
+use axum::http::header::CACHE_CONTROL;
+use axum::http::{HeaderValue, Uri};
 
 use serde::Serialize;
+use sqlx::PgPool;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tower_http::set_header::SetResponseHeaderLayer;
 
+use axum::extract::DefaultBodyLimit;
 use axum::handler::Handler;
-
-use sqlx::postgres::PgPoolOptions;
+use axum::middleware;
+use clap::Parser;
 
 pub type RefStr = &'static str;
 pub type Payload<T> = axum::response::Result<Json<Maybe<T>>, Error>;
@@ -43,31 +73,167 @@ where
     })))
 }
 
+/// `Cache-Control` for public, unauthenticated reads. A zero max-age disables caching.
+fn cache_control(max_age: u64) -> HeaderValue {
+    if max_age == 0 {
+        HeaderValue::from_static("no-cache")
+    } else {
+        HeaderValue::from_str(&format!("public, max-age={}", max_age))
+            .expect("formatted max-age is a valid header value")
+    }
+}
+
+/// Builds every route and layer of the server around its shared state.
+fn app(
+    config: Arc<Config>,
+    pool: PgPool,
+    store: Arc<dyn DiaryStore>,
+    mailer: Arc<dyn Mailer>,
+) -> Router {
+    let public_cache = SetResponseHeaderLayer::overriding(
+        CACHE_CONTROL,
+        cache_control(config.public_cache_max_age),
+    );
+
+    // student and parent sign-ups spend from the same budget
+    let mut register = post(auth::register_student);
+    let mut register_parent = post(parent::register_parent);
+    if config.register_rate_limit > 0 {
+        let register_limit = RateLimitLayer::new(
+            config.register_rate_key,
+            "username",
+            config.register_rate_limit,
+            Duration::from_secs(config.register_rate_window_secs),
+            config.max_body_bytes,
+        );
+        register = register.layer(register_limit.clone());
+        register_parent = register_parent.layer(register_limit);
+    }
+    let mut login = post(auth::login_student);
+    if config.login_rate_limit > 0 {
+        login = login.layer(RateLimitLayer::new(
+            config.login_rate_key,
+            "uuid",
+            config.login_rate_limit,
+            Duration::from_secs(config.login_rate_window_secs),
+            config.max_body_bytes,
+        ));
+    }
+
+    let mut request_reset = post(reset::request_password_reset);
+    if config.password_reset_rate_limit > 0 {
+        request_reset = request_reset.layer(RateLimitLayer::new(
+            RateLimitKey::Both,
+            "email",
+            config.password_reset_rate_limit,
+            Duration::from_secs(config.password_reset_rate_window_secs),
+            config.max_body_bytes,
+        ));
+    }
+
+    // charged per session once a request has authenticated, see `auth::authenticated_session`
+    let session_budget =
+        SessionBudget::new(config.session_rate_per_minute, config.session_rate_burst);
+
+    let mut app = Router::new()
+        .route("/student/register", register)
+        .route(
+            "/student/get_id/:username",
+            get(auth::query_user_id.layer(public_cache.clone())),
+        )
+        .route(
+            "/student/normalize_username",
+            post(auth::normalize_student_username),
+        )
+        .route("/student/link_sso", post(sso::link_external_identity))
+        .route("/student/login_sso", post(sso::login_sso))
+        .route("/student/refresh", post(auth::refresh_student_tokens))
+        .route("/student/request_password_reset", request_reset)
+        .route("/student/reset_password", post(reset::reset_password))
+        .route("/student/verify_email", get(verify::verify_email))
+        .route("/student/2fa/enroll", post(totp::enroll_totp))
+        .route("/student/2fa/confirm", post(totp::confirm_totp))
+        .route("/student/2fa/disable", post(totp::disable_totp))
+        .route("/session/login", login)
+        .route("/session/drop", post(auth::drop_session))
+        .route(
+            "/session/sync",
+            post(auth::sync_session).get(auth::sync_session_with_header),
+        )
+        .route("/session/scoped_token", post(tokens::mint_scoped_token))
+        .route(
+            "/session/scoped_token/introspect",
+            post(tokens::introspect_scoped_token),
+        )
+        .route("/teacher/register", post(teacher::register_teacher))
+        .route(
+            "/teacher/get_id/:username",
+            get(teacher::query_teacher_id.layer(public_cache.clone())),
+        )
+        .route(
+            "/teacher/profile/:username",
+            get(teacher::query_teacher_profile.layer(public_cache)),
+        )
+        .route("/parent/register", register_parent)
+        .route("/parent/link_code", post(parent::issue_link_code))
+        .route("/parent/link", post(parent::link_child))
+        .route("/parent/children", post(parent::list_children))
+        .route("/parent/child_diary", post(parent::list_child_diary))
+        .route("/parent/child_diary/read", post(parent::read_child_diary))
+        .route("/admin/unlock_account", post(roles::unlock_account))
+        .route("/admin/sessions/repair", post(roles::repair_sessions))
+        .route("/admin/signing_keys", post(tokens::report_signing_keys))
+        .route("/status", get(status::server_status))
+        .fallback(frontend::serve_frontend.into_service());
+
+    if config.msgpack {
+        app = app.layer(middleware::from_fn(msgpack::negotiate));
+    }
+    if config.security_headers {
+        app = app.layer(middleware::from_fn(security::secure_headers));
+    }
+    if config.https_policy != HttpsPolicy::Off {
+        app = app.layer(middleware::from_fn(security::enforce_https));
+    }
+
+    app.layer(DefaultBodyLimit::max(config.max_body_bytes))
+        .layer(Extension(pool))
+        .layer(Extension(store))
+        .layer(Extension(mailer))
+        .layer(Extension(session_budget))
+        .layer(Extension(config))
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "OpenDiary HTTP server")]
+struct Cli {
+    /// Validate the configuration from the environment and exit without starting the server
+    #[arg(long)]
+    check_config: bool,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
     env_logger::init();
-    io::prepare_io().await;
-    let dburl = std::env::var("POSTGRES_DATABASE")
-        .expect("`POSTGRES_DATABASE` environment variable not provided!");
+    if cli.check_config {
+        std::process::exit(if config::check_config() { 0 } else { 1 });
+    }
 
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&dburl)
-        .await?;
+    let config = Arc::new(Config::from_env()?);
+    let store = io::open_store(&config).await?;
+    let mailer = mail::open_mailer(&config);
 
-    let app = Router::new()
-        .route("/student/register", post(auth::register_student))
-        .route("/student/get_id/:username", get(auth::query_user_id))
-        .route("/session/login", post(auth::login_student))
-        .route("/session/drop", post(auth::drop_session))
-        .fallback(err::handler404.into_service())
-        .layer(Extension(pool));
+    let pool = db::connect(&config).await?;
+    db::watch_pool(pool.clone(), &config);
+
+    let app = app(config, pool, store, mailer);
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
     log::info!("Starting OpenDiary HTTP Server on http://{}", addr);
 
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await?;
 
     Ok(())
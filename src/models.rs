@@ -2,8 +2,13 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// The columns of `users` that make up a [`UserData`], to select by name rather than with
+/// `*`, which also picks up columns databases migrated by hand may have on top.
+pub const USER_COLUMNS: &str =
+    "uuid, username, name, surname, patronymic, email, password_hash, created_at, email_verified, role";
+
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
-pub struct StudentData {
+pub struct UserData {
     pub uuid: Uuid,
     pub username: String,
     pub name: String,
@@ -12,6 +17,29 @@ pub struct StudentData {
     pub email: String,
     pub password_hash: String,
     pub created_at: DateTime<Utc>,
+    pub email_verified: bool,
+    pub role: Role,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "user_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Student,
+    Teacher,
+    Admin,
+    Parent,
+}
+
+impl Role {
+    /// Whether this role may act as `required`; admins may act as anyone, teachers as students.
+    pub fn grants(self, required: Role) -> bool {
+        match self {
+            Role::Admin => true,
+            Role::Teacher => matches!(required, Role::Teacher | Role::Student),
+            Role::Student | Role::Parent => required == self,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
@@ -19,4 +47,28 @@ pub struct StudentSession {
     pub ssid: String,
     pub belongs_to: Uuid,
     pub expires_at: DateTime<Utc>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PasswordResetToken {
+    pub token: String,
+    pub belongs_to: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct StudentTotp {
+    pub belongs_to: Uuid,
+    pub secret: String,
+    pub confirmed: bool,
+    pub last_used_step: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TeacherProfile {
+    pub belongs_to: Uuid,
+    pub subjects: Vec<String>,
 }
@@ -0,0 +1,177 @@
+use crate::config::Config;
+use crate::err::Error;
+use crate::IntoResponse;
+
+use axum::body::{boxed, Body, Full};
+use axum::http::header::{ACCEPT, CONTENT_LENGTH, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use axum::BoxError;
+use std::sync::Arc;
+
+const MSGPACK: &str = "application/msgpack";
+
+/// Lets clients speak MessagePack instead of JSON: `application/msgpack` request bodies are
+/// transcoded to JSON before the handlers see them, and JSON responses are transcoded back
+/// when the `Accept` header asks for MessagePack.
+pub async fn negotiate(request: Request<Body>, next: Next<Body>) -> Response {
+    let max_body_bytes = request
+        .extensions()
+        .get::<Arc<Config>>()
+        .map_or(usize::MAX, |config| config.max_body_bytes);
+    let wants_msgpack = accepts_msgpack(request.headers());
+
+    let request = if has_content_type(request.headers(), MSGPACK) {
+        match msgpack_to_json(request, max_body_bytes).await {
+            Ok(request) => request,
+            Err(err) => return err.into_response(),
+        }
+    } else {
+        request
+    };
+
+    let response = next.run(request).await;
+    if wants_msgpack && has_content_type(response.headers(), "application/json") {
+        json_to_msgpack(response).await
+    } else {
+        response
+    }
+}
+
+fn accepts_msgpack(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media| media.split(';').next().unwrap_or("").trim() == MSGPACK)
+}
+
+fn has_content_type(headers: &HeaderMap, expected: &str) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(';').next().unwrap_or("").trim() == expected)
+}
+
+async fn msgpack_to_json(request: Request<Body>, limit: usize) -> Result<Request<Body>, Error> {
+    let (mut parts, body) = request.into_parts();
+    let bytes = hyper::body::to_bytes(http_body::Limited::new(body, limit))
+        .await
+        .map_err(|_| Error::PayloadTooLarge {
+            message: "Request body is too large".to_string(),
+        })?;
+    let value: serde_json::Value =
+        rmp_serde::from_slice(&bytes).map_err(|err| Error::InvalidPayload {
+            message: format!("Invalid MessagePack payload: {}", err),
+        })?;
+    let json = serde_json::to_vec(&value).map_err(|err| Error::InvalidPayload {
+        message: format!("Invalid MessagePack payload: {}", err),
+    })?;
+
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    parts
+        .headers
+        .insert(CONTENT_LENGTH, HeaderValue::from(json.len()));
+    Ok(Request::from_parts(parts, Body::from(json)))
+}
+
+async fn json_to_msgpack(response: Response) -> Response {
+    let (mut parts, body) = response.into_parts();
+    let bytes = match hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(err) => return Error::from(BoxError::from(err)).into_response(),
+    };
+    let encoded = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .map_err(|err| err.to_string())
+        .and_then(|value| rmp_serde::to_vec_named(&value).map_err(|err| err.to_string()));
+
+    match encoded {
+        Ok(encoded) => {
+            parts
+                .headers
+                .insert(CONTENT_TYPE, HeaderValue::from_static(MSGPACK));
+            parts
+                .headers
+                .insert(CONTENT_LENGTH, HeaderValue::from(encoded.len()));
+            Response::from_parts(parts, boxed(Full::from(encoded)))
+        }
+        // not actually JSON, hand it over untouched
+        Err(_) => Response::from_parts(parts, boxed(Full::from(bytes))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+
+    use axum::body::Body;
+    use axum::http::header::{ACCEPT, CONTENT_TYPE};
+    use axum::http::{Request, StatusCode};
+    use serde_json::json;
+
+    fn msgpack_post(uri: &str, body: serde_json::Value) -> Request<Body> {
+        Request::post(uri)
+            .header(CONTENT_TYPE, super::MSGPACK)
+            .header(ACCEPT, super::MSGPACK)
+            .body(Body::from(rmp_serde::to_vec_named(&body).unwrap()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_trips_a_request_through_messagepack() {
+        let app = testing::app(testing::config(&[]), testing::lazy_pool()).await;
+
+        let response = testing::send(
+            &app,
+            msgpack_post(
+                "/student/normalize_username",
+                json!({ "username": "  John.DOE " }),
+            ),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], super::MSGPACK);
+        let body: serde_json::Value =
+            rmp_serde::from_slice(&testing::body_bytes(response).await).unwrap();
+        assert_eq!(
+            body,
+            json!({ "success": true, "normalized": "john.doe", "valid": true })
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_json_unless_messagepack_is_accepted() {
+        let app = testing::app(testing::config(&[]), testing::lazy_pool()).await;
+        let mut request = msgpack_post(
+            "/student/normalize_username",
+            json!({ "username": "john.doe" }),
+        );
+        request.headers_mut().remove(ACCEPT);
+
+        let response = testing::send(&app, request).await;
+
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+        assert_eq!(testing::body_json(response).await["normalized"], "john.doe");
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_messagepack() {
+        let app = testing::app(testing::config(&[]), testing::lazy_pool()).await;
+        let request = Request::post("/student/normalize_username")
+            .header(CONTENT_TYPE, super::MSGPACK)
+            .body(Body::from(vec![0xc1]))
+            .unwrap();
+
+        let response = testing::send(&app, request).await;
+
+        assert_eq!(
+            testing::body_json(response).await["error"],
+            "InvalidPayload"
+        );
+    }
+}
@@ -0,0 +1,512 @@
+use crate::auth::{create_account, AuthResult, CreateStudent, SessionBasedResponse};
+use crate::config::Config;
+use crate::err::JsonBody;
+use crate::io::{validate_path, DiaryStore};
+use crate::mail::Mailer;
+use crate::models::Role;
+use crate::roles::{Parent, RequireRole, Teacher};
+use crate::{breaks, proceeds, Error, Payload};
+
+use axum::Extension;
+use chrono::{DateTime, Duration, Utc};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+const LINK_CODE_LEN: usize = 10;
+const LINK_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+
+pub async fn register_parent(
+    JsonBody(parent): JsonBody<CreateStudent>,
+    Extension(pg): Extension<PgPool>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Payload<CreatedParent> {
+    proceeds(CreatedParent {
+        parent_id: create_account(parent, Role::Parent, &pg, mailer.as_ref(), &config).await?,
+    })
+}
+
+/// Issues a single-use code a parent redeems to link to the student. Handing out the
+/// code is how the school approves the link, so only teachers and admins may do it.
+pub async fn issue_link_code(
+    issuer: RequireRole<Teacher>,
+    JsonBody(IssueLinkCode { student_id, .. }): JsonBody<IssueLinkCode>,
+    Extension(pg): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Payload<SessionBasedResponse<LinkCode>> {
+    let is_student = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM users WHERE uuid = $1 AND role = 'student')",
+    )
+    .bind(student_id)
+    .fetch_one(&pg)
+    .await
+    .map_err(Error::from)?;
+    if !is_student {
+        return breaks(Error::UserDoesNotExist {
+            message: format!("Student with uuid `{}` does not exist!", student_id),
+        });
+    }
+
+    let code = generate_link_code();
+    let expires_at = Utc::now() + Duration::hours(config.parent_link_code_ttl_hours);
+    sqlx::query("INSERT INTO parent_link_codes (code, child_id, issued_by, expires_at) VALUES ($1, $2, $3, $4)")
+        .bind(&code)
+        .bind(student_id)
+        .bind(issuer.user.uuid)
+        .bind(expires_at)
+        .execute(&pg)
+        .await
+        .map_err(Error::from)?;
+
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(LinkCode {
+            code,
+            student_id,
+            expires_at,
+        }),
+    })
+}
+
+pub async fn link_child(
+    parent: RequireRole<Parent>,
+    JsonBody(RedeemLinkCode { code, .. }): JsonBody<RedeemLinkCode>,
+    Extension(pg): Extension<PgPool>,
+) -> Payload<SessionBasedResponse<LinkedStudent>> {
+    let redeemed = sqlx::query_as::<_, (Uuid, DateTime<Utc>)>(
+        "DELETE FROM parent_link_codes WHERE code = $1 RETURNING child_id, expires_at",
+    )
+    .bind(code.trim().to_uppercase())
+    .fetch_optional(&pg)
+    .await
+    .map_err(Error::from)?;
+    let child_id = match redeemed {
+        Some((child_id, expires_at)) if expires_at > Utc::now() => child_id,
+        _ => {
+            return breaks(Error::AuthenticationFailure {
+                message: "Link code is invalid or expired".to_string(),
+            })
+        }
+    };
+
+    sqlx::query(
+        "INSERT INTO parent_children (parent_id, child_id, linked_at) VALUES ($1, $2, $3) \
+        ON CONFLICT (parent_id, child_id) DO NOTHING",
+    )
+    .bind(parent.user.uuid)
+    .bind(child_id)
+    .bind(Utc::now())
+    .execute(&pg)
+    .await
+    .map_err(Error::from)?;
+
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(LinkedStudent {
+            student_id: child_id,
+        }),
+    })
+}
+
+pub async fn list_children(
+    parent: RequireRole<Parent>,
+    Extension(pg): Extension<PgPool>,
+) -> Payload<SessionBasedResponse<Children>> {
+    let children = sqlx::query_as::<_, LinkedChild>(
+        "SELECT u.uuid AS student_id, u.username, u.name, u.surname, u.patronymic \
+        FROM parent_children p JOIN users u ON u.uuid = p.child_id \
+        WHERE p.parent_id = $1 ORDER BY p.linked_at",
+    )
+    .bind(parent.user.uuid)
+    .fetch_all(&pg)
+    .await
+    .map_err(Error::from)?;
+
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(Children { children }),
+    })
+}
+
+/// Lists the files of a linked child's diary, which lives under `<student uuid>/` in the store.
+pub async fn list_child_diary(
+    parent: RequireRole<Parent>,
+    JsonBody(ChildDiary { student_id, .. }): JsonBody<ChildDiary>,
+    Extension(pg): Extension<PgPool>,
+    Extension(store): Extension<Arc<dyn DiaryStore>>,
+) -> Payload<SessionBasedResponse<ChildDiaryFiles>> {
+    ensure_linked(parent.user.uuid, student_id, &pg).await?;
+    let prefix = format!("{}/", student_id);
+    let files = store
+        .list(&prefix)
+        .await?
+        .into_iter()
+        .filter_map(|path| path.strip_prefix(&prefix).map(str::to_string))
+        .collect();
+
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(ChildDiaryFiles { student_id, files }),
+    })
+}
+
+/// Reads one file of a linked child's diary. Parents never get write access.
+pub async fn read_child_diary(
+    parent: RequireRole<Parent>,
+    JsonBody(ReadChildDiary {
+        student_id, path, ..
+    }): JsonBody<ReadChildDiary>,
+    Extension(pg): Extension<PgPool>,
+    Extension(store): Extension<Arc<dyn DiaryStore>>,
+) -> Payload<SessionBasedResponse<ChildDiaryFile>> {
+    ensure_linked(parent.user.uuid, student_id, &pg).await?;
+    // only plain names, so the path cannot leave the child's directory whatever the store
+    if validate_path(&path).is_err() {
+        return breaks(Error::InvalidPayload {
+            message: format!("Invalid diary path `{}`", path),
+        });
+    }
+    let bytes = store.read(&format!("{}/{}", student_id, path)).await?;
+    let content = match String::from_utf8(bytes) {
+        Ok(content) => content,
+        Err(_) => {
+            return breaks(Error::InvalidPayload {
+                message: format!("Diary file `{}` is not text", path),
+            })
+        }
+    };
+
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(ChildDiaryFile {
+            student_id,
+            path,
+            content,
+        }),
+    })
+}
+
+async fn ensure_linked(parent_id: Uuid, child_id: Uuid, pg: &PgPool) -> Result<(), Error> {
+    let linked = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS(SELECT 1 FROM parent_children WHERE parent_id = $1 AND child_id = $2)",
+    )
+    .bind(parent_id)
+    .bind(child_id)
+    .fetch_one(pg)
+    .await
+    .map_err(Error::from)?;
+    if linked {
+        Ok(())
+    } else {
+        Err(Error::Forbidden {
+            message: "Student is not linked to this parent".to_string(),
+        })
+    }
+}
+
+fn generate_link_code() -> String {
+    let mut rng = thread_rng();
+    (0..LINK_CODE_LEN)
+        .map(|_| LINK_CODE_ALPHABET[rng.gen_range(0..LINK_CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatedParent {
+    parent_id: Uuid,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IssueLinkCode {
+    pub ssid: String,
+    pub student_id: Uuid,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkCode {
+    code: String,
+    student_id: Uuid,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedeemLinkCode {
+    pub ssid: String,
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkedStudent {
+    student_id: Uuid,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct LinkedChild {
+    student_id: Uuid,
+    username: String,
+    name: String,
+    surname: String,
+    patronymic: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Children {
+    children: Vec<LinkedChild>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChildDiary {
+    pub ssid: String,
+    pub student_id: Uuid,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChildDiaryFiles {
+    student_id: Uuid,
+    files: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReadChildDiary {
+    pub ssid: String,
+    pub student_id: Uuid,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChildDiaryFile {
+    student_id: Uuid,
+    path: String,
+    content: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::LocalStore;
+    use crate::testing;
+
+    use axum::http::StatusCode;
+    use axum::Router;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    struct Family {
+        app: Router,
+        child: Uuid,
+        sibling: Uuid,
+        store_root: PathBuf,
+        parent_ssid: String,
+        teacher_ssid: String,
+    }
+
+    /// A student with a diary of one entry, a teacher and a registered, logged in parent.
+    /// The parent is not linked to the other student, whose diary sits next to the child's.
+    async fn family(pool: &PgPool) -> Family {
+        let child = testing::insert_user(pool, "child", "password").await;
+        let sibling = testing::insert_user(pool, "sibling", "password").await;
+        let teacher = testing::insert_user(pool, "teacher", "password").await;
+        testing::set_role(pool, teacher, Role::Teacher).await;
+        let teacher_ssid =
+            testing::insert_session(pool, teacher, Utc::now() + Duration::hours(1)).await;
+
+        let store_root = testing::temp_dir();
+        let store = LocalStore::new(&store_root).await.unwrap();
+        store
+            .write(&format!("{}/2024/09-02.md", child), b"First day of school")
+            .await
+            .unwrap();
+        store
+            .write(
+                &format!("{}/2024/09-02.md", sibling),
+                b"Not for other parents",
+            )
+            .await
+            .unwrap();
+        let app = testing::app_with_store(testing::config(&[]), pool.clone(), Arc::new(store));
+
+        let registered = post(
+            &app,
+            "/parent/register",
+            json!({
+                "username": "parent",
+                "name": "Pat",
+                "surname": "Parent",
+                "email": "parent@example.org",
+                "password": "correct horse battery",
+            }),
+        )
+        .await;
+        let parent: Uuid = serde_json::from_value(registered["parent_id"].clone()).unwrap();
+        let parent_ssid =
+            testing::insert_session(pool, parent, Utc::now() + Duration::hours(1)).await;
+
+        Family {
+            app,
+            child,
+            sibling,
+            store_root,
+            parent_ssid,
+            teacher_ssid,
+        }
+    }
+
+    async fn post(app: &Router, uri: &str, body: serde_json::Value) -> serde_json::Value {
+        testing::body_json(testing::send(app, testing::post(uri, body)).await).await
+    }
+
+    async fn issue_code(family: &Family) -> String {
+        let body = post(
+            &family.app,
+            "/parent/link_code",
+            json!({ "ssid": family.teacher_ssid, "student_id": family.child }),
+        )
+        .await;
+        assert_eq!(body["auth_result"], "Success");
+        body["code"].as_str().unwrap().to_string()
+    }
+
+    async fn link(family: &Family, code: &str) -> serde_json::Value {
+        post(
+            &family.app,
+            "/parent/link",
+            json!({ "ssid": family.parent_ssid, "code": code }),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn linked_parents_read_the_diary() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let family = family(&pool).await;
+        let code = issue_code(&family).await;
+
+        let body = link(&family, &code.to_lowercase()).await;
+        assert_eq!(body["auth_result"], "Success");
+        assert_eq!(body["student_id"], family.child.to_string());
+
+        let body = post(
+            &family.app,
+            "/parent/children",
+            json!({ "ssid": family.parent_ssid }),
+        )
+        .await;
+        assert_eq!(body["children"][0]["student_id"], family.child.to_string());
+        assert_eq!(body["children"][0]["username"], "child");
+
+        let diary = json!({ "ssid": family.parent_ssid, "student_id": family.child });
+        let body = post(&family.app, "/parent/child_diary", diary).await;
+        assert_eq!(body["files"], json!(["2024/09-02.md"]));
+
+        let body = post(
+            &family.app,
+            "/parent/child_diary/read",
+            json!({
+                "ssid": family.parent_ssid,
+                "student_id": family.child,
+                "path": "2024/09-02.md",
+            }),
+        )
+        .await;
+        assert_eq!(body["content"], "First day of school");
+    }
+
+    #[tokio::test]
+    async fn codes_are_single_use_and_expire() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let family = family(&pool).await;
+
+        let code = issue_code(&family).await;
+        assert_eq!(link(&family, &code).await["auth_result"], "Success");
+        assert_eq!(link(&family, &code).await["error"], "AuthenticationFailure");
+
+        let code = issue_code(&family).await;
+        sqlx::query("UPDATE parent_link_codes SET expires_at = $2 WHERE code = $1")
+            .bind(&code)
+            .bind(Utc::now() - Duration::seconds(1))
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(link(&family, &code).await["error"], "AuthenticationFailure");
+    }
+
+    #[tokio::test]
+    async fn only_the_school_issues_codes() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let family = family(&pool).await;
+        let child_ssid =
+            testing::insert_session(&pool, family.child, Utc::now() + Duration::hours(1)).await;
+
+        for ssid in [&family.parent_ssid, &child_ssid] {
+            let response = testing::send(
+                &family.app,
+                testing::post(
+                    "/parent/link_code",
+                    json!({ "ssid": ssid, "student_id": family.child }),
+                ),
+            )
+            .await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+
+        // codes are for students only
+        let body = post(
+            &family.app,
+            "/parent/link_code",
+            json!({ "ssid": family.teacher_ssid, "student_id": Uuid::new_v4() }),
+        )
+        .await;
+        assert_eq!(body["error"], "UserDoesNotExist");
+    }
+
+    #[tokio::test]
+    async fn unlinked_diaries_stay_closed() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let family = family(&pool).await;
+        let read = |ssid: &str, path: &str| {
+            testing::send(
+                &family.app,
+                testing::post(
+                    "/parent/child_diary/read",
+                    json!({ "ssid": ssid, "student_id": family.child, "path": path }),
+                ),
+            )
+        };
+
+        let response = read(&family.parent_ssid, "2024/09-02.md").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // students cannot pass for parents, not even their own
+        let child_ssid =
+            testing::insert_session(&pool, family.child, Utc::now() + Duration::hours(1)).await;
+        let response = read(&child_ssid, "2024/09-02.md").await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        // nor does linking reach beyond the child's directory
+        link(&family, &issue_code(&family).await).await;
+        let sibling_file = format!("{}/2024/09-02.md", family.sibling);
+        for path in [
+            "../../etc/passwd".to_string(),
+            format!("../{}", sibling_file),
+            format!("{}/../{}", family.child, sibling_file),
+            format!("/{}", sibling_file),
+            family.store_root.join(&sibling_file).display().to_string(),
+        ] {
+            let body = testing::body_json(read(&family.parent_ssid, &path).await).await;
+            assert_eq!(body["success"], false, "{}", path);
+            assert_eq!(body["error"], "InvalidPayload", "{}", path);
+            assert!(body.get("content").is_none(), "{}", path);
+        }
+    }
+}
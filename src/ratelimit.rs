@@ -0,0 +1,406 @@
+use crate::err::Error;
+use crate::security::client_ip;
+use crate::IntoResponse;
+
+use axum::body::{Body, Bytes};
+use axum::http::Request;
+use axum::response::Response;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service};
+
+/// Number of tracked keys above which expired windows are swept on the next check.
+const SWEEP_THRESHOLD: usize = 4096;
+
+/// What a rate limit is keyed by.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RateLimitKey {
+    /// The address of the client, see `CLIENT_IP_HEADER`.
+    Ip,
+    /// A field of the JSON request body identifying the target account.
+    Account,
+    /// Both of the above, each with its own budget; exhausting either rejects.
+    Both,
+}
+
+impl FromStr for RateLimitKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ip" => Ok(Self::Ip),
+            "account" => Ok(Self::Account),
+            "both" => Ok(Self::Both),
+            other => Err(format!(
+                "expected `ip`, `account` or `both`, got `{}`",
+                other
+            )),
+        }
+    }
+}
+
+/// A request budget shared by all clones of a [`RateLimitLayer`].
+pub trait Limiter: std::fmt::Debug + Send + Sync {
+    /// Counts a hit for every key, returning how long to wait if any of them is over the limit.
+    fn check(&self, keys: &[String]) -> Result<(), Duration>;
+}
+
+/// Fixed-window request limiter.
+#[derive(Debug)]
+pub struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Limiter for RateLimiter {
+    fn check(&self, keys: &[String]) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() > SWEEP_THRESHOLD {
+            windows.retain(|_, (started, _)| now.duration_since(*started) < self.window);
+        }
+
+        let mut retry_after = None;
+        for key in keys {
+            let (started, hits) = windows.entry(key.clone()).or_insert((now, 0));
+            if now.duration_since(*started) >= self.window {
+                *started = now;
+                *hits = 0;
+            }
+            *hits += 1;
+            if *hits > self.limit {
+                let wait = self.window - now.duration_since(*started);
+                retry_after = Some(retry_after.map_or(wait, |other: Duration| other.max(wait)));
+            }
+        }
+        retry_after.map_or(Ok(()), Err)
+    }
+}
+
+/// Token bucket limiter: each key holds up to `burst` tokens, refilled at `per_minute`.
+#[derive(Debug)]
+pub struct TokenBucket {
+    burst: f64,
+    per_sec: f64,
+    buckets: Mutex<HashMap<String, (Instant, f64)>>,
+}
+
+impl TokenBucket {
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        Self {
+            burst: burst.max(1) as f64,
+            per_sec: per_minute as f64 / 60.0,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn refilled(&self, tokens: f64, elapsed: Duration) -> f64 {
+        (tokens + elapsed.as_secs_f64() * self.per_sec).min(self.burst)
+    }
+}
+
+impl Limiter for TokenBucket {
+    fn check(&self, keys: &[String]) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > SWEEP_THRESHOLD {
+            buckets.retain(|_, (updated, tokens)| {
+                self.refilled(*tokens, now.duration_since(*updated)) < self.burst
+            });
+        }
+
+        let mut retry_after = None;
+        for key in keys {
+            let (updated, tokens) = buckets.entry(key.clone()).or_insert((now, self.burst));
+            *tokens = self.refilled(*tokens, now.duration_since(*updated));
+            *updated = now;
+            if *tokens >= 1.0 {
+                *tokens -= 1.0;
+            } else {
+                let wait =
+                    Duration::from_secs_f64((1.0 - *tokens) / self.per_sec.max(f64::EPSILON));
+                retry_after = Some(retry_after.map_or(wait, |other: Duration| other.max(wait)));
+            }
+        }
+        retry_after.map_or(Ok(()), Err)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<dyn Limiter>,
+    key: RateLimitKey,
+    account_field: &'static str,
+    max_body_bytes: usize,
+}
+
+impl RateLimitLayer {
+    /// `account_field` names the JSON body field used for [`RateLimitKey::Account`];
+    /// bodies larger than `max_body_bytes` are not buffered and get no account key.
+    pub fn new(
+        key: RateLimitKey,
+        account_field: &'static str,
+        limit: u32,
+        window: Duration,
+        max_body_bytes: usize,
+    ) -> Self {
+        Self {
+            limiter: Arc::new(RateLimiter::new(limit, window)),
+            key,
+            account_field,
+            max_body_bytes,
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    layer: RateLimitLayer,
+}
+
+impl<S> Service<Request<Body>> for RateLimit<S>
+where
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // the clone may not be ready, keep the instance we polled
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            let (parts, body) = request.into_parts();
+            let mut keys = Vec::new();
+
+            if matches!(layer.key, RateLimitKey::Ip | RateLimitKey::Both) {
+                let ip = client_ip(&parts.extensions, &parts.headers)
+                    .map(|ip| ip.to_string())
+                    .unwrap_or_else(|| "unknown".to_string());
+                keys.push(format!("ip:{}", ip));
+            }
+
+            let body = if matches!(layer.key, RateLimitKey::Account | RateLimitKey::Both) {
+                let limited = http_body::Limited::new(body, layer.max_body_bytes);
+                match hyper::body::to_bytes(limited).await {
+                    Ok(bytes) => {
+                        if let Some(account) = account_key(&bytes, layer.account_field) {
+                            keys.push(format!("account:{}", account));
+                        }
+                        Body::from(bytes)
+                    }
+                    Err(_) => {
+                        return Ok(Error::PayloadTooLarge {
+                            message: "Request body is too large".to_string(),
+                        }
+                        .into_response())
+                    }
+                }
+            } else {
+                body
+            };
+
+            if let Err(retry_after) = layer.limiter.check(&keys) {
+                return Ok(rate_limited(retry_after).into_response());
+            }
+            inner.call(Request::from_parts(parts, body)).await
+        })
+    }
+}
+
+fn account_key(body: &Bytes, field: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+    match value.get(field)? {
+        serde_json::Value::String(account) => Some(account.trim().to_lowercase()),
+        other => Some(other.to_string()),
+    }
+}
+
+/// Budget shared by all authenticated requests of a session, refilled at
+/// `SESSION_RATE_PER_MINUTE` up to `SESSION_RATE_BURST`. The default one never runs out.
+#[derive(Debug, Clone, Default)]
+pub struct SessionBudget(Option<Arc<TokenBucket>>);
+
+impl SessionBudget {
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        Self((per_minute > 0).then(|| Arc::new(TokenBucket::new(per_minute, burst))))
+    }
+
+    /// Spends one request from the budget of `key`.
+    pub fn spend(&self, key: &str) -> Result<(), Error> {
+        match &self.0 {
+            Some(bucket) => bucket.check(&[key.to_string()]).map_err(rate_limited),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn rate_limited(retry_after: Duration) -> Error {
+    let seconds = retry_after.as_secs().max(1);
+    Error::RateLimited {
+        message: format!("Too many requests, retry in {} seconds", seconds),
+        retry_after: seconds,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    use axum::http::header::RETRY_AFTER;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Extension, Router};
+    use serde_json::json;
+
+    #[test]
+    fn fixed_window_rejects_past_the_limit_per_key() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let keys = ["ip:203.0.113.7".to_string()];
+
+        assert!(limiter.check(&keys).is_ok());
+        assert!(limiter.check(&keys).is_ok());
+        let retry_after = limiter.check(&keys).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(60));
+        assert!(retry_after > Duration::from_secs(59));
+
+        assert!(limiter.check(&["ip:198.51.100.2".to_string()]).is_ok());
+    }
+
+    #[test]
+    fn fixed_window_resets_after_the_window() {
+        let limiter = RateLimiter::new(1, Duration::from_millis(50));
+        let keys = ["account:student".to_string()];
+
+        assert!(limiter.check(&keys).is_ok());
+        assert!(limiter.check(&keys).is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.check(&keys).is_ok());
+    }
+
+    #[test]
+    fn token_bucket_allows_bursts_and_refills() {
+        let bucket = TokenBucket::new(60, 2);
+        let keys = ["session:a".to_string()];
+
+        assert!(bucket.check(&keys).is_ok());
+        assert!(bucket.check(&keys).is_ok());
+        let retry_after = bucket.check(&keys).unwrap_err();
+        assert!(retry_after <= Duration::from_secs(1));
+
+        assert!(bucket.check(&["session:b".to_string()]).is_ok());
+        std::thread::sleep(retry_after);
+        assert!(bucket.check(&keys).is_ok());
+    }
+
+    fn limited_app(key: RateLimitKey) -> Router {
+        let layer = RateLimitLayer::new(key, "uuid", 2, Duration::from_secs(60), 1024);
+        Router::new()
+            .route("/login", post(|| async { "ok" }).layer(layer))
+            .layer(Extension(Arc::new(testing::config(&[]))))
+    }
+
+    fn login_from(ip: &str, uuid: &str) -> Request<Body> {
+        let mut request = testing::post("/login", json!({ "uuid": uuid }));
+        request
+            .headers_mut()
+            .insert("x-forwarded-for", ip.parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn limits_by_forwarded_client_ip() {
+        let app = limited_app(RateLimitKey::Ip);
+
+        for uuid in ["a", "b"] {
+            let response = testing::send(&app, login_from("203.0.113.7", uuid)).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = testing::send(&app, login_from("203.0.113.7", "c")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = response.headers()[RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((59..=60).contains(&retry_after));
+        let body = testing::body_json(response).await;
+        assert_eq!(body["error"], "RateLimited");
+        assert_eq!(body["retry_after"], retry_after);
+
+        // all requests reach us from the same proxy, but other clients keep their budget
+        let response = testing::send(&app, login_from("198.51.100.2", "a")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn limits_by_account() {
+        let app = limited_app(RateLimitKey::Account);
+
+        for ip in ["203.0.113.7", "198.51.100.2"] {
+            let response = testing::send(&app, login_from(ip, "Student")).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        // the account key is case-insensitive
+        let response = testing::send(&app, login_from("192.0.2.1", "student")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let response = testing::send(&app, login_from("192.0.2.1", "other")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn limits_by_either_key_with_both() {
+        let app = limited_app(RateLimitKey::Both);
+
+        testing::send(&app, login_from("203.0.113.7", "a")).await;
+        testing::send(&app, login_from("203.0.113.7", "b")).await;
+        // fresh account, exhausted IP
+        let response = testing::send(&app, login_from("203.0.113.7", "c")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        testing::send(&app, login_from("198.51.100.2", "d")).await;
+        testing::send(&app, login_from("198.51.100.3", "d")).await;
+        // fresh IP, exhausted account
+        let response = testing::send(&app, login_from("198.51.100.4", "d")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+}
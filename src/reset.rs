@@ -0,0 +1,288 @@
+use crate::auth::{check_new_password, generate_ssid, hash_password};
+use crate::config::Config;
+use crate::err::JsonBody;
+use crate::mail::Mailer;
+use crate::models::{PasswordResetToken, UserData, USER_COLUMNS};
+use crate::{breaks, proceeds, Error, Payload};
+
+use axum::Extension;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Emails a single-use reset link to the account with the given email. The response is
+/// the same whether or not such an account exists.
+pub async fn request_password_reset(
+    JsonBody(RequestPasswordReset { email }): JsonBody<RequestPasswordReset>,
+    Extension(pg): Extension<PgPool>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Payload<PasswordResetRequested> {
+    let user = sqlx::query_as::<_, UserData>(&format!(
+        "SELECT {} FROM users WHERE lower(email) = lower($1) LIMIT 1",
+        USER_COLUMNS
+    ))
+    .bind(email.trim())
+    .fetch_optional(&pg)
+    .await
+    .map_err(Error::from)?;
+
+    if let Some(user) = user {
+        let token = generate_ssid();
+        let expires_at = Utc::now() + Duration::minutes(config.password_reset_ttl_minutes);
+        // only the latest link stays usable
+        sqlx::query("DELETE FROM password_reset_tokens WHERE belongs_to = $1")
+            .bind(user.uuid)
+            .execute(&pg)
+            .await
+            .map_err(Error::from)?;
+        sqlx::query(
+            "INSERT INTO password_reset_tokens (token, belongs_to, expires_at) VALUES ($1, $2, $3)",
+        )
+        .bind(&token)
+        .bind(user.uuid)
+        .bind(expires_at)
+        .execute(&pg)
+        .await
+        .map_err(Error::from)?;
+
+        let body = format!(
+            "Hello, {}!\n\nUse the link below to choose a new OpenDiary password. \
+            It expires in {} minutes.\n\n{}/reset_password?token={}\n\n\
+            If you did not ask for this, you can ignore this email.\n",
+            user.name,
+            config.password_reset_ttl_minutes,
+            config.public_url.trim_end_matches('/'),
+            token
+        );
+        // a failure must not tell the caller that the account exists
+        if let Err(err) = mailer
+            .send(&user.email, "Reset your OpenDiary password", &body)
+            .await
+        {
+            log::error!("Could not send password reset email: {:#}", err);
+        }
+    }
+
+    proceeds(PasswordResetRequested { email })
+}
+
+/// Sets a new password with a token from [`request_password_reset`], consuming the token,
+/// dropping every session of the account and lifting any lockout.
+pub async fn reset_password(
+    JsonBody(ResetPassword { token, password }): JsonBody<ResetPassword>,
+    Extension(pg): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Payload<PasswordReset> {
+    if let Err(err) = check_new_password(&password, &config) {
+        return breaks(err);
+    }
+
+    let reset = sqlx::query_as::<_, PasswordResetToken>(
+        "DELETE FROM password_reset_tokens WHERE token = $1 RETURNING *",
+    )
+    .bind(&token)
+    .fetch_optional(&pg)
+    .await
+    .map_err(Error::from)?;
+    let reset = match reset {
+        Some(reset) if reset.expires_at > Utc::now() => reset,
+        _ => {
+            return breaks(Error::AuthenticationFailure {
+                message: "Password reset token is invalid or expired".to_string(),
+            })
+        }
+    };
+
+    let password_hash = hash_password(&password)?;
+    let mut tx = pg.begin().await.map_err(Error::from)?;
+    let updated = sqlx::query("UPDATE users SET password_hash = $1 WHERE uuid = $2")
+        .bind(&password_hash)
+        .bind(reset.belongs_to)
+        .execute(&mut tx)
+        .await
+        .map_err(Error::from)?;
+    if updated.rows_affected() == 0 {
+        return breaks(Error::UserDoesNotExist {
+            message: format!("User with uuid `{}` does not exist!", reset.belongs_to),
+        });
+    }
+    sqlx::query("DELETE FROM user_sessions WHERE belongs_to = $1")
+        .bind(reset.belongs_to)
+        .execute(&mut tx)
+        .await
+        .map_err(Error::from)?;
+    sqlx::query("DELETE FROM refresh_tokens WHERE belongs_to = $1")
+        .bind(reset.belongs_to)
+        .execute(&mut tx)
+        .await
+        .map_err(Error::from)?;
+    // proving control of the mailbox lifts a lockout
+    sqlx::query("DELETE FROM login_failures WHERE belongs_to = $1")
+        .bind(reset.belongs_to)
+        .execute(&mut tx)
+        .await
+        .map_err(Error::from)?;
+    tx.commit().await.map_err(Error::from)?;
+
+    proceeds(PasswordReset {
+        student_id: reset.belongs_to,
+    })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RequestPasswordReset {
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PasswordResetRequested {
+    email: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PasswordReset {
+    student_id: Uuid,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, RecordingMailer};
+
+    use pbkdf2::password_hash::{PasswordHash, PasswordVerifier};
+    use pbkdf2::Pbkdf2;
+
+    async fn request(
+        pool: &PgPool,
+        mailer: &Arc<RecordingMailer>,
+        email: &str,
+    ) -> serde_json::Value {
+        testing::json(
+            request_password_reset(
+                JsonBody(RequestPasswordReset {
+                    email: email.to_string(),
+                }),
+                Extension(pool.clone()),
+                Extension(mailer.clone() as Arc<dyn Mailer>),
+                Extension(Arc::new(testing::config(&[]))),
+            )
+            .await,
+        )
+    }
+
+    async fn reset(pool: &PgPool, token: &str, password: &str) -> serde_json::Value {
+        testing::json(
+            reset_password(
+                JsonBody(ResetPassword {
+                    token: token.to_string(),
+                    password: password.to_string(),
+                }),
+                Extension(pool.clone()),
+                Extension(Arc::new(testing::config(&[]))),
+            )
+            .await,
+        )
+    }
+
+    async fn email_of(pool: &PgPool, uuid: Uuid) -> String {
+        sqlx::query_scalar("SELECT email FROM users WHERE uuid = $1")
+            .bind(uuid)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn password_matches(pool: &PgPool, uuid: Uuid, password: &str) -> bool {
+        let hash: String = sqlx::query_scalar("SELECT password_hash FROM users WHERE uuid = $1")
+            .bind(uuid)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        let hash = PasswordHash::new(&hash).unwrap();
+        Pbkdf2.verify_password(password.as_bytes(), &hash).is_ok()
+    }
+
+    #[tokio::test]
+    async fn resets_the_password_once_and_drops_sessions() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let mailer = Arc::new(RecordingMailer::default());
+        let student = testing::insert_user(&pool, "student", "old password").await;
+        let ssid = testing::insert_session(&pool, student, Utc::now() + Duration::hours(1)).await;
+        let email = email_of(&pool, student).await;
+
+        let response = request(&pool, &mailer, &email.to_uppercase()).await;
+        assert_eq!(response["success"], true);
+        let sent = mailer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, email);
+        let token = testing::link_param(&sent[0].2, "token").unwrap();
+
+        let response = reset(&pool, &token, "new password").await;
+        assert_eq!(response["student_id"], student.to_string());
+        assert!(password_matches(&pool, student, "new password").await);
+        let session: Option<String> =
+            sqlx::query_scalar("SELECT ssid FROM user_sessions WHERE ssid = $1")
+                .bind(&ssid)
+                .fetch_optional(&pool)
+                .await
+                .unwrap();
+        assert!(session.is_none());
+
+        let response = reset(&pool, &token, "another password").await;
+        assert_eq!(response["error"], "AuthenticationFailure");
+        assert!(password_matches(&pool, student, "new password").await);
+    }
+
+    #[tokio::test]
+    async fn only_the_latest_unexpired_token_works() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let mailer = Arc::new(RecordingMailer::default());
+        let student = testing::insert_user(&pool, "student", "old password").await;
+        let email = email_of(&pool, student).await;
+
+        request(&pool, &mailer, &email).await;
+        request(&pool, &mailer, &email).await;
+        let sent = mailer.sent();
+        let first = testing::link_param(&sent[0].2, "token").unwrap();
+        let second = testing::link_param(&sent[1].2, "token").unwrap();
+
+        let response = reset(&pool, &first, "new password").await;
+        assert_eq!(response["error"], "AuthenticationFailure");
+
+        sqlx::query("UPDATE password_reset_tokens SET expires_at = $1")
+            .bind(Utc::now() - Duration::minutes(1))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let response = reset(&pool, &second, "new password").await;
+        assert_eq!(response["error"], "AuthenticationFailure");
+        assert!(password_matches(&pool, student, "old password").await);
+    }
+
+    #[tokio::test]
+    async fn answers_alike_for_unknown_emails() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let mailer = Arc::new(RecordingMailer::default());
+
+        let response = request(&pool, &mailer, "nobody@example.org").await;
+
+        assert_eq!(response["success"], true);
+        assert_eq!(response["email"], "nobody@example.org");
+        assert!(mailer.sent().is_empty());
+    }
+}
@@ -0,0 +1,343 @@
+use crate::auth::{authenticated_session, AuthResult, ClientInfo, SessionBasedResponse};
+use crate::config::Config;
+use crate::err::{Fine, JsonBody};
+use crate::lockout::lift_lockouts;
+use crate::models::{Role, StudentSession, UserData, USER_COLUMNS};
+use crate::ratelimit::SessionBudget;
+use crate::{breaks, proceeds, Error, IntoResponse, Payload};
+
+use axum::body::Body;
+use axum::extract::{FromRequest, RequestParts};
+use axum::response::Response;
+use axum::{async_trait, Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// A role a route can demand through [`RequireRole`].
+pub trait RequiredRole: Send + Sync {
+    const ROLE: Role;
+}
+
+pub struct Teacher;
+
+impl RequiredRole for Teacher {
+    const ROLE: Role = Role::Teacher;
+}
+
+pub struct Admin;
+
+impl RequiredRole for Admin {
+    const ROLE: Role = Role::Admin;
+}
+
+/// Authenticates the `ssid` of the JSON body and checks that its user holds role `R`.
+/// The body is left in place for the handler's own extractors.
+pub struct RequireRole<R> {
+    pub session: StudentSession,
+    pub user: UserData,
+    _role: PhantomData<R>,
+}
+
+#[async_trait]
+impl<R: RequiredRole> FromRequest<Body> for RequireRole<R> {
+    type Rejection = Response;
+
+    async fn from_request(req: &mut RequestParts<Body>) -> Result<Self, Self::Rejection> {
+        let Extension(pg) = Extension::<PgPool>::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let Extension(config) = Extension::<Arc<Config>>::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let Extension(budget) = Extension::<SessionBudget>::from_request(req)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let client = match ClientInfo::from_request(req).await {
+            Ok(client) => client,
+            Err(never) => match never {},
+        };
+
+        let body = req.take_body().unwrap_or_default();
+        let bytes = hyper::body::to_bytes(http_body::Limited::new(body, config.max_body_bytes))
+            .await
+            .map_err(|_| {
+                Error::PayloadTooLarge {
+                    message: "Request body is too large".to_string(),
+                }
+                .into_response()
+            })?;
+        let ssid = serde_json::from_slice::<SessionId>(&bytes)
+            .ok()
+            .map(|body| body.ssid);
+        *req.body_mut() = Some(Body::from(bytes));
+
+        let session = authenticated_session(ssid, &client, &pg, &config, &budget)
+            .await
+            .map_err(IntoResponse::into_response)?
+            .ok_or_else(|| {
+                Json(Fine(SessionBasedResponse::<()> {
+                    auth_result: AuthResult::InvalidSession,
+                    value: None,
+                }))
+                .into_response()
+            })?;
+        let user = sqlx::query_as::<_, UserData>(&format!(
+            "SELECT {} FROM users WHERE uuid = $1",
+            USER_COLUMNS
+        ))
+        .bind(session.belongs_to)
+        .fetch_one(&pg)
+        .await
+        .map_err(|err| Error::from(err).into_response())?;
+
+        if !user.role.grants(R::ROLE) {
+            return Err(Error::Forbidden {
+                message: format!("This action requires the {:?} role", R::ROLE),
+            }
+            .into_response());
+        }
+        Ok(Self {
+            session,
+            user,
+            _role: PhantomData,
+        })
+    }
+}
+
+pub struct Parent;
+
+impl RequiredRole for Parent {
+    const ROLE: Role = Role::Parent;
+}
+
+/// Lifts a lockout from repeated failed logins before it runs out.
+pub async fn unlock_account(
+    admin: RequireRole<Admin>,
+    JsonBody(UnlockAccount { uuid, .. }): JsonBody<UnlockAccount>,
+    Extension(pg): Extension<PgPool>,
+) -> Payload<SessionBasedResponse<AccountUnlocked>> {
+    let exists =
+        sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM users WHERE uuid = $1)")
+            .bind(uuid)
+            .fetch_one(&pg)
+            .await
+            .map_err(Error::from)?;
+    if !exists {
+        return breaks(Error::UserDoesNotExist {
+            message: format!("User with uuid `{}` does not exist!", uuid),
+        });
+    }
+
+    let unlocked = lift_lockouts(uuid, &pg).await?;
+    log::info!("Admin {} unlocked account {}", admin.user.uuid, uuid);
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(AccountUnlocked {
+            student_id: uuid,
+            unlocked,
+        }),
+    })
+}
+
+/// Deletes sessions whose user no longer exists, such as those of users removed by hand.
+/// They can no longer authenticate, this only reclaims their rows.
+pub async fn repair_sessions(
+    admin: RequireRole<Admin>,
+    Extension(pg): Extension<PgPool>,
+) -> Payload<SessionBasedResponse<SessionsRepaired>> {
+    let removed = sqlx::query(
+        "DELETE FROM user_sessions s WHERE NOT EXISTS (SELECT 1 FROM users u WHERE u.uuid = s.belongs_to)",
+    )
+    .execute(&pg)
+    .await
+    .map_err(Error::from)?
+    .rows_affected();
+
+    log::info!(
+        "Admin {} removed {} orphaned session(s)",
+        admin.user.uuid,
+        removed
+    );
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(SessionsRepaired { removed }),
+    })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct SessionId {
+    ssid: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UnlockAccount {
+    pub ssid: String,
+    pub uuid: Uuid,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountUnlocked {
+    student_id: Uuid,
+    unlocked: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionsRepaired {
+    removed: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::Router;
+    use chrono::{Duration, Utc};
+    use serde_json::json;
+
+    /// A route only teachers (and admins) get through, answering with the caller's role.
+    fn teachers_only(pool: PgPool) -> Router {
+        async fn handler(teacher: RequireRole<Teacher>) -> Json<Role> {
+            Json(teacher.user.role)
+        }
+        Router::new()
+            .route("/teachers_only", post(handler))
+            .layer(Extension(pool))
+            .layer(Extension(SessionBudget::default()))
+            .layer(Extension(Arc::new(testing::config(&[]))))
+    }
+
+    #[test]
+    fn roles_grant_themselves_and_those_below() {
+        use Role::*;
+        let all = [Student, Teacher, Admin, Parent];
+
+        for required in all {
+            assert!(Admin.grants(required));
+            assert!(required.grants(required));
+        }
+        assert!(Teacher.grants(Student));
+        for (role, required) in [
+            (Student, Teacher),
+            (Student, Admin),
+            (Student, Parent),
+            (Teacher, Admin),
+            (Teacher, Parent),
+            (Parent, Student),
+            (Parent, Teacher),
+            (Parent, Admin),
+        ] {
+            assert!(!role.grants(required), "{:?} grants {:?}", role, required);
+        }
+    }
+
+    #[tokio::test]
+    async fn require_role_checks_the_session_user() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let app = teachers_only(pool.clone());
+        let call = |ssid: String| {
+            testing::send(
+                &app,
+                testing::post("/teachers_only", json!({ "ssid": ssid })),
+            )
+        };
+        let mut ssids = Vec::new();
+        for (username, role) in [
+            ("student", Role::Student),
+            ("teacher", Role::Teacher),
+            ("admin", Role::Admin),
+        ] {
+            let user = testing::insert_user(&pool, username, "password").await;
+            testing::set_role(&pool, user, role).await;
+            ssids.push(testing::insert_session(&pool, user, Utc::now() + Duration::hours(1)).await);
+        }
+
+        let response = call(ssids[0].clone()).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(testing::body_json(response).await["error"], "Forbidden");
+        for (ssid, role) in [(&ssids[1], "teacher"), (&ssids[2], "admin")] {
+            let response = call(ssid.clone()).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(testing::body_json(response).await, role);
+        }
+
+        let response = call("no-such-session".to_string()).await;
+        assert_eq!(
+            testing::body_json(response).await["auth_result"],
+            "InvalidSession"
+        );
+    }
+
+    #[tokio::test]
+    async fn repair_removes_only_orphaned_sessions() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let admin = testing::insert_user(&pool, "admin", "password").await;
+        testing::set_role(&pool, admin, Role::Admin).await;
+        let admin_ssid =
+            testing::insert_session(&pool, admin, Utc::now() + Duration::hours(1)).await;
+        let student = testing::insert_user(&pool, "student", "password").await;
+        testing::insert_session(&pool, student, Utc::now() + Duration::hours(1)).await;
+        let orphan =
+            testing::insert_session(&pool, Uuid::new_v4(), Utc::now() + Duration::hours(1)).await;
+        let app = testing::app(testing::config(&[]), pool.clone()).await;
+
+        // an orphaned session does not authenticate
+        let response = testing::send(
+            &app,
+            testing::post("/session/sync", json!({ "ssid": orphan })),
+        )
+        .await;
+        assert_eq!(
+            testing::body_json(response).await["auth_result"],
+            "InvalidSession"
+        );
+
+        let response = testing::send(
+            &app,
+            testing::post("/admin/sessions/repair", json!({ "ssid": admin_ssid })),
+        )
+        .await;
+        let body = testing::body_json(response).await;
+        assert_eq!(body["auth_result"], "Success");
+        assert_eq!(body["removed"], 1);
+
+        let remaining: i64 = sqlx::query_scalar("SELECT count(*) FROM user_sessions")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, 2);
+    }
+
+    #[tokio::test]
+    async fn repair_requires_an_admin() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let ssid = testing::insert_session(&pool, student, Utc::now() + Duration::hours(1)).await;
+        testing::insert_session(&pool, Uuid::new_v4(), Utc::now() + Duration::hours(1)).await;
+        let app = testing::app(testing::config(&[]), pool.clone()).await;
+
+        let response = testing::send(
+            &app,
+            testing::post("/admin/sessions/repair", json!({ "ssid": ssid })),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let remaining: i64 = sqlx::query_scalar("SELECT count(*) FROM user_sessions")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, 2);
+    }
+}
@@ -0,0 +1,338 @@
+use crate::config::{ClientIpHeader, Config, HttpsPolicy};
+use crate::{Error, IntoResponse};
+
+use axum::extract::ConnectInfo;
+use axum::http::header::{
+    CONTENT_SECURITY_POLICY, HOST, LOCATION, STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS,
+    X_FRAME_OPTIONS,
+};
+use axum::http::{Extensions, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Paths that stay reachable over plain HTTP so health checks keep working.
+const HTTPS_EXEMPT_PATHS: &[&str] = &["/status"];
+
+fn is_over_tls<B>(request: &Request<B>) -> bool {
+    request.uri().scheme_str() == Some("https")
+        || request
+            .headers()
+            .get("x-forwarded-proto")
+            .is_some_and(|proto| proto == "https")
+}
+
+/// The address of the client, taken from the header named by `CLIENT_IP_HEADER` and
+/// falling back to the peer address when the proxy did not send it.
+pub fn client_ip(extensions: &Extensions, headers: &HeaderMap) -> Option<IpAddr> {
+    let source = extensions
+        .get::<Arc<Config>>()
+        .map_or(ClientIpHeader::None, |config| config.client_ip_header);
+    let forwarded = match source {
+        ClientIpHeader::XForwardedFor => last_header_element(headers, "x-forwarded-for")
+            .and_then(|element| parse_forwarded_ip(element.trim())),
+        ClientIpHeader::Forwarded => last_header_element(headers, "forwarded")
+            .and_then(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.trim().split_once('=')?;
+                    name.eq_ignore_ascii_case("for").then_some(value)
+                })
+            })
+            .and_then(|value| parse_forwarded_ip(value.trim().trim_matches('"'))),
+        ClientIpHeader::None => None,
+    };
+    forwarded.or_else(|| {
+        extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+    })
+}
+
+/// The last comma-separated element over all values of `name`, i.e. the one added
+/// by the proxy closest to us.
+fn last_header_element<'h>(headers: &'h HeaderMap, name: &str) -> Option<&'h str> {
+    headers
+        .get_all(name)
+        .iter()
+        .next_back()?
+        .to_str()
+        .ok()?
+        .rsplit(',')
+        .next()
+}
+
+/// Accepts a bare address, `1.2.3.4:port` and `[v6]:port`.
+fn parse_forwarded_ip(value: &str) -> Option<IpAddr> {
+    value
+        .parse::<IpAddr>()
+        .or_else(|_| value.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .or_else(|_| value.trim_start_matches('[').trim_end_matches(']').parse())
+        .ok()
+}
+
+/// Redirects or rejects plain HTTP requests according to `HTTPS_POLICY`.
+pub async fn enforce_https<B>(request: Request<B>, next: Next<B>) -> Response {
+    let policy = request
+        .extensions()
+        .get::<Arc<Config>>()
+        .map_or(HttpsPolicy::Off, |config| config.https_policy);
+    if policy == HttpsPolicy::Off
+        || is_over_tls(&request)
+        || HTTPS_EXEMPT_PATHS.contains(&request.uri().path())
+    {
+        return next.run(request).await;
+    }
+
+    match (policy, https_location(&request)) {
+        (HttpsPolicy::Redirect, Some(location)) => {
+            (StatusCode::MOVED_PERMANENTLY, [(LOCATION, location)]).into_response()
+        }
+        _ => Error::HttpsRequired {
+            message: "This server only accepts requests over HTTPS".to_string(),
+        }
+        .into_response(),
+    }
+}
+
+fn https_location<B>(request: &Request<B>) -> Option<HeaderValue> {
+    let host = forwarded_host(request.headers())?;
+    let path = request
+        .uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    HeaderValue::from_str(&format!("https://{}{}", host, path)).ok()
+}
+
+fn forwarded_host(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get("x-forwarded-host")
+        .or_else(|| headers.get(HOST))
+        .and_then(|host| host.to_str().ok())
+}
+
+/// Adds the configured security headers to every response.
+/// HSTS is only sent for requests that reached us over TLS, as reported by the proxy.
+pub async fn secure_headers<B>(request: Request<B>, next: Next<B>) -> Response {
+    let config = request.extensions().get::<Arc<Config>>().cloned();
+    let over_tls = is_over_tls(&request);
+
+    let mut response = next.run(request).await;
+    let config = match config {
+        Some(config) => config,
+        None => return response,
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    if let Ok(value) = HeaderValue::from_str(&config.frame_options) {
+        headers.insert(X_FRAME_OPTIONS, value);
+    }
+    if let Ok(value) = HeaderValue::from_str(&config.content_security_policy) {
+        headers.insert(CONTENT_SECURITY_POLICY, value);
+    }
+    if let (true, Some(max_age)) = (over_tls, config.hsts_max_age) {
+        if let Ok(value) = HeaderValue::from_str(&format!("max-age={}", max_age)) {
+            headers.insert(STRICT_TRANSPORT_SECURITY, value);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+
+    use axum::body::Body;
+    use axum::http::header::{
+        CONTENT_SECURITY_POLICY, LOCATION, STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS,
+        X_FRAME_OPTIONS,
+    };
+    use axum::http::{Request, StatusCode};
+    use serde_json::json;
+
+    fn normalize() -> Request<Body> {
+        testing::post(
+            "/student/normalize_username",
+            json!({ "username": "student" }),
+        )
+    }
+
+    #[tokio::test]
+    async fn adds_security_headers_to_responses() {
+        let config = testing::config(&[
+            ("SECURITY_HEADERS", "true"),
+            ("SECURITY_FRAME_OPTIONS", "SAMEORIGIN"),
+            ("SECURITY_HSTS_MAX_AGE", "600"),
+        ]);
+        let app = testing::app(config, testing::lazy_pool()).await;
+
+        let response = testing::send(&app, normalize()).await;
+
+        let headers = response.headers();
+        assert_eq!(headers[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(headers[X_FRAME_OPTIONS], "SAMEORIGIN");
+        assert_eq!(
+            headers[CONTENT_SECURITY_POLICY],
+            "default-src 'self'; frame-ancestors 'none'"
+        );
+        // the request did not come over TLS
+        assert!(headers.get(STRICT_TRANSPORT_SECURITY).is_none());
+    }
+
+    #[tokio::test]
+    async fn sends_hsts_only_over_tls() {
+        let config = testing::config(&[
+            ("SECURITY_HEADERS", "true"),
+            ("SECURITY_HSTS_MAX_AGE", "600"),
+        ]);
+        let app = testing::app(config, testing::lazy_pool()).await;
+
+        let mut request = normalize();
+        request
+            .headers_mut()
+            .insert("x-forwarded-proto", "https".parse().unwrap());
+        let response = testing::send(&app, request).await;
+
+        assert_eq!(response.headers()[STRICT_TRANSPORT_SECURITY], "max-age=600");
+    }
+
+    #[tokio::test]
+    async fn leaves_responses_alone_when_disabled() {
+        let app = testing::app(testing::config(&[]), testing::lazy_pool()).await;
+
+        let response = testing::send(&app, normalize()).await;
+
+        assert!(response.headers().get(X_CONTENT_TYPE_OPTIONS).is_none());
+        assert!(response.headers().get(X_FRAME_OPTIONS).is_none());
+    }
+
+    fn plain_http(mut request: Request<Body>) -> Request<Body> {
+        let headers = request.headers_mut();
+        headers.insert("x-forwarded-proto", "http".parse().unwrap());
+        headers.insert("host", "diary.example.org".parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn redirects_plain_http_to_https() {
+        let config = testing::config(&[("HTTPS_POLICY", "redirect")]);
+        let app = testing::app(config, testing::lazy_pool()).await;
+
+        let response = testing::send(
+            &app,
+            plain_http(testing::get("/student/get_id/student?x=1")),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            response.headers()[LOCATION],
+            "https://diary.example.org/student/get_id/student?x=1"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_plain_http() {
+        let config = testing::config(&[("HTTPS_POLICY", "reject")]);
+        let app = testing::app(config, testing::lazy_pool()).await;
+
+        let response = testing::send(&app, plain_http(normalize())).await;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(testing::body_json(response).await["error"], "HttpsRequired");
+    }
+
+    #[tokio::test]
+    async fn lets_https_and_health_checks_through() {
+        for policy in ["redirect", "reject"] {
+            let config = testing::config(&[("HTTPS_POLICY", policy)]);
+            let app = testing::app(config, testing::lazy_pool()).await;
+
+            let response = testing::send(&app, plain_http(testing::get("/status"))).await;
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let mut request = normalize();
+            request
+                .headers_mut()
+                .insert("x-forwarded-proto", "https".parse().unwrap());
+            let response = testing::send(&app, request).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    fn client_ip_with(source: &str, headers: &[(&'static str, &str)]) -> Option<std::net::IpAddr> {
+        let mut extensions = axum::http::Extensions::new();
+        extensions.insert(std::sync::Arc::new(testing::config(&[(
+            "CLIENT_IP_HEADER",
+            source,
+        )])));
+        let peer: std::net::SocketAddr = "127.0.0.1:40000".parse().unwrap();
+        extensions.insert(axum::extract::ConnectInfo(peer));
+        let mut map = axum::http::HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        super::client_ip(&extensions, &map)
+    }
+
+    #[test]
+    fn reads_the_client_ip_appended_by_the_proxy() {
+        let ip = |s: &str| Some(s.parse().unwrap());
+
+        assert_eq!(
+            client_ip_with(
+                "x-forwarded-for",
+                &[("x-forwarded-for", "10.0.0.1, 203.0.113.7")]
+            ),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client_ip_with(
+                "x-forwarded-for",
+                &[
+                    ("x-forwarded-for", "10.0.0.1"),
+                    ("x-forwarded-for", "[2001:db8::1]:4711")
+                ]
+            ),
+            ip("2001:db8::1")
+        );
+        assert_eq!(
+            client_ip_with(
+                "forwarded",
+                &[(
+                    "forwarded",
+                    "for=10.0.0.1, for=\"[2001:db8::7]:4711\";proto=https"
+                )]
+            ),
+            ip("2001:db8::7")
+        );
+        assert_eq!(
+            client_ip_with(
+                "forwarded",
+                &[("forwarded", "proto=https;For=198.51.100.2")]
+            ),
+            ip("198.51.100.2")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_peer_address() {
+        let peer = Some("127.0.0.1".parse().unwrap());
+
+        assert_eq!(client_ip_with("x-forwarded-for", &[]), peer);
+        assert_eq!(
+            client_ip_with("x-forwarded-for", &[("x-forwarded-for", "garbage")]),
+            peer
+        );
+        assert_eq!(
+            client_ip_with("forwarded", &[("forwarded", "for=unknown")]),
+            peer
+        );
+        // headers are ignored unless configured
+        assert_eq!(
+            client_ip_with("none", &[("x-forwarded-for", "203.0.113.7")]),
+            peer
+        );
+    }
+}
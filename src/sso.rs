@@ -0,0 +1,363 @@
+use crate::auth::{
+    admit_student, authenticated_session, start_session, AuthResult, ClientInfo, EnsureSession,
+    LoggedInStudent, SessionBasedResponse,
+};
+use crate::config::Config;
+use crate::db::is_unique_violation;
+use crate::err::JsonBody;
+use crate::lockout::check_lockout;
+use crate::mail::Mailer;
+use crate::models::{UserData, USER_COLUMNS};
+use crate::ratelimit::SessionBudget;
+use crate::{breaks, proceeds, Error, Payload};
+
+use axum::Extension;
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Asymmetric algorithms accepted for id tokens; shared-secret ones would let anyone
+/// holding the public JWKS forge tokens.
+const ID_TOKEN_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+];
+
+pub async fn link_external_identity(
+    client: ClientInfo,
+    JsonBody(EnsureSession { ssid, value }): JsonBody<EnsureSession<IdentityToken>>,
+    Extension(pg): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(budget): Extension<SessionBudget>,
+) -> Payload<SessionBasedResponse<LinkedIdentity>> {
+    let identity = match verify_id_token(&value.id_token, &config) {
+        Ok(identity) => identity,
+        Err(err) => return breaks(err),
+    };
+
+    let session = match authenticated_session(Some(ssid), &client, &pg, &config, &budget).await? {
+        Some(session) => session,
+        None => {
+            return proceeds(SessionBasedResponse {
+                auth_result: AuthResult::InvalidSession,
+                value: None,
+            })
+        }
+    };
+
+    let linked_at = Utc::now();
+    let res = sqlx::query(
+        "INSERT INTO external_identities (issuer, subject, belongs_to, linked_at) \
+         VALUES ($1, $2, $3, $4)",
+    )
+    .bind(&identity.iss)
+    .bind(&identity.sub)
+    .bind(session.belongs_to)
+    .bind(linked_at)
+    .execute(&pg)
+    .await;
+    match res {
+        Err(err) if is_unique_violation(&err) => {
+            return breaks(Error::IdentityAlreadyLinked {
+                message: "This external identity is already linked to an account".to_string(),
+            })
+        }
+        res => res.map_err(Error::from)?,
+    };
+
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(LinkedIdentity {
+            issuer: identity.iss,
+            subject: identity.sub,
+            student_id: session.belongs_to,
+            linked_at,
+        }),
+    })
+}
+
+pub async fn login_sso(
+    client: ClientInfo,
+    JsonBody(login): JsonBody<LoginSso>,
+    Extension(pg): Extension<PgPool>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Payload<LoggedInStudent> {
+    let identity = match verify_id_token(&login.id_token, &config) {
+        Ok(identity) => identity,
+        Err(err) => return breaks(err),
+    };
+
+    let student = sqlx::query_as::<_, UserData>(&format!(
+        "SELECT {} FROM users WHERE uuid = \
+        (SELECT belongs_to FROM external_identities WHERE issuer = $1 AND subject = $2)",
+        USER_COLUMNS
+    ))
+    .bind(&identity.iss)
+    .bind(&identity.sub)
+    .fetch_optional(&pg)
+    .await
+    .map_err(Error::from)?;
+    let student = match student {
+        Some(student) => student,
+        None => {
+            return breaks(Error::UserDoesNotExist {
+                message: "No account is linked to this external identity".to_string(),
+            })
+        }
+    };
+
+    // the identity provider stands in for the password, everything else still applies
+    if let Err(err) = check_lockout(student.uuid, &client, &pg).await {
+        return breaks(err);
+    }
+    if let Err(err) = admit_student(
+        &student,
+        login.totp_code.as_deref(),
+        &client,
+        &pg,
+        mailer.as_ref(),
+        &config,
+    )
+    .await
+    {
+        return breaks(err);
+    }
+
+    proceeds(start_session(student.uuid, &client, &pg, &config).await?)
+}
+
+/// Validates an OIDC id token against `OIDC_ISSUER`, `OIDC_AUDIENCE` and the keys of `OIDC_JWKS_PATH`.
+pub fn verify_id_token(token: &str, config: &Config) -> Result<IdTokenClaims, Error> {
+    let (issuer, audience, jwks) = match (
+        &config.oidc_issuer,
+        &config.oidc_audience,
+        &config.oidc_jwks,
+    ) {
+        (Some(issuer), Some(audience), Some(jwks)) => (issuer, audience, jwks),
+        _ => {
+            return Err(Error::InvalidIdentityToken {
+                message: "Single sign-on is not configured on this server".to_string(),
+            })
+        }
+    };
+    let rejected = |message: String| Error::InvalidIdentityToken { message };
+
+    let header = decode_header(token).map_err(|err| rejected(err.to_string()))?;
+    if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+        return Err(rejected(format!(
+            "Unsupported id token algorithm {:?}",
+            header.alg
+        )));
+    }
+    let jwk = header
+        .kid
+        .as_deref()
+        .and_then(|kid| jwks.find(kid))
+        .ok_or_else(|| rejected("Id token was not signed by a trusted key".to_string()))?;
+    let key = DecodingKey::from_jwk(jwk).map_err(|err| rejected(err.to_string()))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[issuer]);
+    validation.set_audience(&[audience]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+    decode::<IdTokenClaims>(token, &key, &validation)
+        .map(|data| data.claims)
+        .map_err(|err| rejected(err.to_string()))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdentityToken {
+    pub id_token: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoginSso {
+    pub id_token: String,
+    /// TOTP or recovery code, required once two-factor authentication is enabled
+    pub totp_code: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkedIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub student_id: Uuid,
+    pub linked_at: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    use serde_json::json;
+
+    async fn link(app: &axum::Router, ssid: &str, id_token: &str) -> serde_json::Value {
+        let response = testing::send(
+            app,
+            testing::post(
+                "/student/link_sso",
+                json!({ "ssid": ssid, "id_token": id_token }),
+            ),
+        )
+        .await;
+        testing::body_json(response).await
+    }
+
+    async fn login(app: &axum::Router, id_token: &str) -> serde_json::Value {
+        let response = testing::send(
+            app,
+            testing::post("/student/login_sso", json!({ "id_token": id_token })),
+        )
+        .await;
+        testing::body_json(response).await
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_a_linked_identity() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let ssid =
+            testing::insert_session(&pool, student, Utc::now() + chrono::Duration::hours(1)).await;
+        let app = testing::app(testing::config(&testing::oidc_vars()), pool.clone()).await;
+        let id_token = testing::id_token(testing::OIDC_ISSUER, "pupil-42", true);
+
+        let body = login(&app, &id_token).await;
+        assert_eq!(body["error"], "UserDoesNotExist");
+
+        let body = link(&app, &ssid, &id_token).await;
+        assert_eq!(body["auth_result"], "Success");
+        assert_eq!(body["subject"], "pupil-42");
+        assert_eq!(body["student_id"], student.to_string());
+
+        let body = login(&app, &id_token).await;
+        assert_eq!(body["success"], true);
+        assert_eq!(body["student_id"], student.to_string());
+        assert!(body["session_id"].is_string());
+
+        // one identity belongs to one account
+        let other = testing::insert_user(&pool, "other", "password").await;
+        let other_ssid =
+            testing::insert_session(&pool, other, Utc::now() + chrono::Duration::hours(1)).await;
+        let body = link(&app, &other_ssid, &id_token).await;
+        assert_eq!(body["error"], "IdentityAlreadyLinked");
+    }
+
+    #[tokio::test]
+    async fn checks_lockout_and_verification_like_password_logins() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let ssid =
+            testing::insert_session(&pool, student, Utc::now() + chrono::Duration::hours(1)).await;
+        let mailer = Arc::new(testing::RecordingMailer::default());
+        let app = testing::app_with_mailer(
+            testing::config(
+                &[
+                    testing::oidc_vars().as_slice(),
+                    &[("EMAIL_VERIFICATION_GRACE_HOURS", "0")],
+                ]
+                .concat(),
+            ),
+            pool.clone(),
+            mailer.clone(),
+        )
+        .await;
+        let id_token = testing::id_token(testing::OIDC_ISSUER, "pupil-42", true);
+        assert_eq!(link(&app, &ssid, &id_token).await["auth_result"], "Success");
+
+        sqlx::query(
+            "INSERT INTO login_failures (belongs_to, client, failures, locked_until) \
+            VALUES ($1, '127.0.0.1', 0, $2)",
+        )
+        .bind(student)
+        .bind(Utc::now() + chrono::Duration::minutes(5))
+        .execute(&pool)
+        .await
+        .unwrap();
+        assert_eq!(login(&app, &id_token).await["error"], "AccountLocked");
+
+        crate::lockout::lift_lockouts(student, &pool).await.unwrap();
+        sqlx::query("UPDATE users SET email_verified = false WHERE uuid = $1")
+            .bind(student)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(login(&app, &id_token).await["error"], "EmailNotVerified");
+        assert_eq!(mailer.sent().len(), 1);
+    }
+
+    #[test]
+    fn rejects_tokens_of_untrusted_issuers_and_keys() {
+        let config = testing::config(&testing::oidc_vars());
+
+        assert!(verify_id_token(
+            &testing::id_token(testing::OIDC_ISSUER, "pupil-42", true),
+            &config
+        )
+        .is_ok());
+        for token in [
+            testing::id_token("https://sso.elsewhere.example", "pupil-42", true),
+            testing::id_token(testing::OIDC_ISSUER, "pupil-42", false),
+            "not a token".to_string(),
+        ] {
+            assert!(matches!(
+                verify_id_token(&token, &config),
+                Err(Error::InvalidIdentityToken { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_shared_secret_algorithms() {
+        let config = testing::config(&testing::oidc_vars());
+        let header = jsonwebtoken::Header {
+            kid: Some("test-key".to_string()),
+            ..jsonwebtoken::Header::new(Algorithm::HS256)
+        };
+        let claims = json!({
+            "iss": testing::OIDC_ISSUER,
+            "sub": "pupil-42",
+            "aud": testing::OIDC_AUDIENCE,
+            "exp": (Utc::now() + chrono::Duration::minutes(5)).timestamp(),
+        });
+        // the JWKS is public, so anyone could use it as an HMAC secret
+        let token = jsonwebtoken::encode(
+            &header,
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(include_bytes!("../testdata/oidc/jwks.json")),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            verify_id_token(&token, &config),
+            Err(Error::InvalidIdentityToken { .. })
+        ));
+    }
+
+    #[test]
+    fn refuses_tokens_when_sso_is_not_configured() {
+        let token = testing::id_token(testing::OIDC_ISSUER, "pupil-42", true);
+
+        assert!(verify_id_token(&token, &testing::config(&[])).is_err());
+    }
+}
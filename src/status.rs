@@ -0,0 +1,36 @@
+use crate::config::Config;
+use crate::{proceeds, Payload};
+
+use axum::Extension;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::sync::Arc;
+
+pub async fn server_status(
+    Extension(pg): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Payload<ServerStatus> {
+    let open = pg.size();
+    let idle = pg.num_idle() as u32;
+    proceeds(ServerStatus {
+        pool: PoolStatus {
+            open_connections: open,
+            idle_connections: idle,
+            in_use_connections: open.saturating_sub(idle),
+            max_connections: config.db_max_connections,
+        },
+    })
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerStatus {
+    pub pool: PoolStatus,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PoolStatus {
+    pub open_connections: u32,
+    pub idle_connections: u32,
+    pub in_use_connections: u32,
+    pub max_connections: u32,
+}
@@ -0,0 +1,258 @@
+use crate::auth::{
+    account_not_stored, insert_account, new_account, normalize_username, AuthResult, CreateStudent,
+    SessionBasedResponse,
+};
+use crate::config::Config;
+use crate::db::with_retry;
+use crate::err::{BoundedPath, JsonBody};
+use crate::mail::Mailer;
+use crate::models::{Role, TeacherProfile, UserData, USER_COLUMNS};
+use crate::roles::{Admin, RequireRole};
+use crate::verify::send_verification_email;
+use crate::{proceeds, Error, Payload};
+
+use axum::Extension;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Creates a teacher account together with its profile. Only admins may add teachers.
+pub async fn register_teacher(
+    admin: RequireRole<Admin>,
+    JsonBody(CreateTeacher {
+        account, subjects, ..
+    }): JsonBody<CreateTeacher>,
+    Extension(pg): Extension<PgPool>,
+    Extension(mailer): Extension<Arc<dyn Mailer>>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Payload<SessionBasedResponse<CreatedTeacher>> {
+    let subjects: Vec<String> = subjects
+        .iter()
+        .map(|subject| subject.trim().to_string())
+        .filter(|subject| !subject.is_empty())
+        .collect();
+
+    let teacher = new_account(account, Role::Teacher, &pg, &config).await?;
+    // a teacher without a profile would be left behind if only the account got stored
+    with_retry(config.db_max_retries, || async {
+        let mut tx = pg.begin().await?;
+        insert_account(&teacher, &mut tx).await?;
+        sqlx::query("INSERT INTO teacher_profiles (belongs_to, subjects) VALUES ($1, $2)")
+            .bind(teacher.uuid)
+            .bind(&subjects)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await
+    })
+    .await
+    .map_err(account_not_stored)?;
+    send_verification_email(&teacher, mailer.as_ref(), &config).await;
+    let teacher_id = teacher.uuid;
+    log::info!(
+        "Admin {} registered teacher {}",
+        admin.user.uuid,
+        teacher_id
+    );
+
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(CreatedTeacher { teacher_id }),
+    })
+}
+
+pub async fn query_teacher_id(
+    BoundedPath(username): BoundedPath<String>,
+    Extension(pg): Extension<PgPool>,
+) -> Payload<CreatedTeacher> {
+    proceeds(CreatedTeacher {
+        teacher_id: find_teacher(&username, &pg).await?.uuid,
+    })
+}
+
+pub async fn query_teacher_profile(
+    BoundedPath(username): BoundedPath<String>,
+    Extension(pg): Extension<PgPool>,
+) -> Payload<TeacherProfileView> {
+    let teacher = find_teacher(&username, &pg).await?;
+    let profile = sqlx::query_as::<_, TeacherProfile>(
+        "SELECT belongs_to, subjects FROM teacher_profiles WHERE belongs_to = $1",
+    )
+    .bind(teacher.uuid)
+    .fetch_optional(&pg)
+    .await
+    .map_err(Error::from)?;
+
+    proceeds(TeacherProfileView {
+        teacher_id: teacher.uuid,
+        username: teacher.username,
+        name: teacher.name,
+        surname: teacher.surname,
+        patronymic: teacher.patronymic,
+        subjects: profile.map(|profile| profile.subjects).unwrap_or_default(),
+    })
+}
+
+/// Looks up a teacher by username; other accounts are reported as missing.
+async fn find_teacher(username: &str, pg: &PgPool) -> Result<UserData, Error> {
+    let username = normalize_username(username);
+    if username.is_empty() {
+        return Err(Error::InvalidPayload {
+            message: "`username` parameter was empty".to_string(),
+        });
+    }
+
+    let teacher = sqlx::query_as::<_, UserData>(&format!(
+        "SELECT {} FROM users WHERE lower(username) = $1 AND role = 'teacher' LIMIT 1",
+        USER_COLUMNS
+    ))
+    .bind(&username)
+    .fetch_optional(pg)
+    .await
+    .map_err(Error::from)?;
+    teacher.ok_or_else(|| Error::UserDoesNotExist {
+        message: format!("Teacher with name `{}` does not exist!", username),
+    })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreateTeacher {
+    pub ssid: String,
+    #[serde(flatten)]
+    pub account: CreateStudent,
+    #[serde(default)]
+    pub subjects: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CreatedTeacher {
+    teacher_id: Uuid,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize)]
+pub struct TeacherProfileView {
+    teacher_id: Uuid,
+    username: String,
+    name: String,
+    surname: String,
+    patronymic: Option<String>,
+    subjects: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::Role;
+    use crate::testing;
+
+    use axum::http::StatusCode;
+    use chrono::{Duration, Utc};
+    use serde_json::json;
+    use sqlx::PgPool;
+
+    async fn admin_session(pool: &PgPool) -> String {
+        let admin = testing::insert_user(pool, "admin", "password").await;
+        testing::set_role(pool, admin, Role::Admin).await;
+        testing::insert_session(pool, admin, Utc::now() + Duration::hours(1)).await
+    }
+
+    fn registration(ssid: &str) -> serde_json::Value {
+        json!({
+            "ssid": ssid,
+            "username": "Ms.Smith",
+            "name": "Jane",
+            "surname": "Smith",
+            "email": "smith@example.org",
+            "password": "correct horse battery",
+            "subjects": ["Maths", " Physics ", ""],
+        })
+    }
+
+    async fn users_named(pool: &PgPool, username: &str) -> i64 {
+        sqlx::query_scalar("SELECT count(*) FROM users WHERE username = $1")
+            .bind(username)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn admins_register_teachers_with_profiles() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let ssid = admin_session(&pool).await;
+        let app = testing::app(testing::config(&[]), pool.clone()).await;
+
+        let response = testing::send(
+            &app,
+            testing::post("/teacher/register", registration(&ssid)),
+        )
+        .await;
+        let body = testing::body_json(response).await;
+        assert_eq!(body["auth_result"], "Success");
+        let teacher_id = body["teacher_id"].clone();
+
+        let body =
+            testing::body_json(testing::send(&app, testing::get("/teacher/get_id/ms.smith")).await)
+                .await;
+        assert_eq!(body["teacher_id"], teacher_id);
+        let body = testing::body_json(
+            testing::send(&app, testing::get("/teacher/profile/ms.smith")).await,
+        )
+        .await;
+        assert_eq!(body["teacher_id"], teacher_id);
+        assert_eq!(body["surname"], "Smith");
+        assert_eq!(body["subjects"], json!(["Maths", "Physics"]));
+
+        // students are not teachers
+        testing::insert_user(&pool, "pupil", "password").await;
+        let body =
+            testing::body_json(testing::send(&app, testing::get("/teacher/profile/pupil")).await)
+                .await;
+        assert_eq!(body["error"], "UserDoesNotExist");
+    }
+
+    #[tokio::test]
+    async fn only_admins_register_teachers() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let teacher = testing::insert_user(&pool, "teacher", "password").await;
+        testing::set_role(&pool, teacher, Role::Teacher).await;
+        let ssid = testing::insert_session(&pool, teacher, Utc::now() + Duration::hours(1)).await;
+        let app = testing::app(testing::config(&[]), pool.clone()).await;
+
+        let response = testing::send(
+            &app,
+            testing::post("/teacher/register", registration(&ssid)),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(users_named(&pool, "ms.smith").await, 0);
+    }
+
+    #[tokio::test]
+    async fn stores_no_account_when_the_profile_fails() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let ssid = admin_session(&pool).await;
+        let app = testing::app(testing::config(&[]), pool.clone()).await;
+        sqlx::query("DROP TABLE teacher_profiles")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let response = testing::send(
+            &app,
+            testing::post("/teacher/register", registration(&ssid)),
+        )
+        .await;
+
+        assert_eq!(testing::body_json(response).await["error"], "InternalError");
+        assert_eq!(users_named(&pool, "ms.smith").await, 0);
+    }
+}
@@ -0,0 +1,322 @@
+//! Helpers shared by the unit tests.
+//!
+//! Tests that need Postgres read its URL from `TEST_DATABASE_URL` and are skipped when it is
+//! not set. Each of them gets a fresh database with `schemas.sql` applied, so they can run in
+//! parallel against one server.
+
+use crate::config::Config;
+use crate::err::Nothing;
+use crate::io::{DiaryStore, LocalStore};
+use crate::mail::{LogMailer, Mailer};
+use crate::models::Role;
+use crate::Payload;
+
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::response::Response;
+use axum::Router;
+
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::{Connection, Executor, PgConnection, PgPool};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Once};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+const SIGNING_SECRET: &str = "test-signing-secret-that-is-long-enough";
+
+/// A configuration with every setting at its default, except for `vars`.
+pub fn config(vars: &[(&str, &str)]) -> Config {
+    let mut vars: HashMap<String, String> = vars
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+    vars.entry("POSTGRES_DATABASE".to_string())
+        .or_insert_with(|| "postgres://localhost/opendiary".to_string());
+    vars.entry("TOKEN_SIGNING_SECRET".to_string())
+        .or_insert_with(|| SIGNING_SECRET.to_string());
+    Config::from_vars(|key| vars.get(key).cloned()).expect("test configuration is valid")
+}
+
+/// A pool on a fresh database of the `TEST_DATABASE_URL` server, or `None` when it is not set.
+pub async fn pool() -> Option<PgPool> {
+    let pool = empty_pool().await?;
+    pool.execute(include_str!("../schemas.sql"))
+        .await
+        .expect("schemas.sql applies");
+    Some(pool)
+}
+
+/// Same as [`pool`], but without any schema applied.
+pub async fn empty_pool() -> Option<PgPool> {
+    let url = match std::env::var("TEST_DATABASE_URL") {
+        Ok(url) if !url.is_empty() => url,
+        _ => {
+            eprintln!("TEST_DATABASE_URL not set, skipping");
+            return None;
+        }
+    };
+
+    // a database rather than a schema per test, as sqlx looks custom types up by name alone
+    let database = format!("test_{}", uuid::Uuid::new_v4().simple());
+    let mut conn = PgConnection::connect(&url)
+        .await
+        .expect("TEST_DATABASE_URL is reachable");
+    conn.execute(format!("CREATE DATABASE {}", database).as_str())
+        .await
+        .expect("test database is created");
+    conn.close().await.ok();
+
+    let options = PgConnectOptions::from_str(&url)
+        .expect("TEST_DATABASE_URL is a valid URL")
+        .database(&database);
+    let pool = PgPoolOptions::new()
+        .max_connections(5)
+        .connect_with(options)
+        .await
+        .expect("test pool connects");
+    Some(pool)
+}
+
+/// The JSON a handler result is sent to clients as.
+pub fn json<T: Serialize>(payload: Payload<T>) -> serde_json::Value {
+    match payload {
+        Ok(body) => serde_json::to_value(body.0),
+        Err(err) => serde_json::to_value(Nothing::<()>(err)),
+    }
+    .expect("payload serializes")
+}
+
+/// Stores a verified student directly, bypassing registration, with `password` as its password.
+pub async fn insert_user(pool: &PgPool, username: &str, password: &str) -> Uuid {
+    let uuid = Uuid::new_v4();
+    sqlx::query(
+        "INSERT INTO users (uuid, username, name, surname, email, password_hash, created_at, email_verified) \
+         VALUES ($1, $2, 'Test', 'User', $3, $4, $5, true)",
+    )
+    .bind(uuid)
+    .bind(username)
+    .bind(format!("{}@example.org", uuid.simple()))
+    .bind(crate::auth::hash_password(password).expect("password hashes"))
+    .bind(Utc::now())
+    .execute(pool)
+    .await
+    .expect("test user is stored");
+    uuid
+}
+
+pub async fn set_role(pool: &PgPool, uuid: Uuid, role: Role) {
+    sqlx::query("UPDATE users SET role = $1 WHERE uuid = $2")
+        .bind(role)
+        .bind(uuid)
+        .execute(pool)
+        .await
+        .expect("test user role is updated");
+}
+
+/// Stores a session for `belongs_to` that expires at `expires_at`, returning its id.
+pub async fn insert_session(
+    pool: &PgPool,
+    belongs_to: Uuid,
+    expires_at: chrono::DateTime<Utc>,
+) -> String {
+    let ssid = crate::auth::generate_ssid();
+    sqlx::query("INSERT INTO user_sessions (ssid, expires_at, belongs_to) VALUES ($1, $2, $3)")
+        .bind(&ssid)
+        .bind(expires_at)
+        .bind(belongs_to)
+        .execute(pool)
+        .await
+        .expect("test session is stored");
+    ssid
+}
+
+/// A pool that never connects unless used, for tests that do not reach the database.
+pub fn lazy_pool() -> PgPool {
+    PgPoolOptions::new()
+        .connect_lazy("postgres://localhost/opendiary")
+        .expect("lazy pool is created")
+}
+
+/// A new empty directory under the system temporary directory.
+pub fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("opendiary-test-{}", Uuid::new_v4().simple()));
+    std::fs::create_dir_all(&dir).expect("temporary directory is created");
+    dir
+}
+
+/// The full router, with diaries in a temporary directory and mail written to the log.
+pub async fn app(config: Config, pool: PgPool) -> Router {
+    app_with_mailer(config, pool, Arc::new(LogMailer)).await
+}
+
+/// Same as [`app`], but sending mail through `mailer`.
+pub async fn app_with_mailer(config: Config, pool: PgPool, mailer: Arc<dyn Mailer>) -> Router {
+    let store = LocalStore::new(temp_dir())
+        .await
+        .expect("local store opens");
+    crate::app(Arc::new(config), pool, Arc::new(store), mailer)
+}
+
+/// Same as [`app`], but keeping diaries in `store`.
+pub fn app_with_store(config: Config, pool: PgPool, store: Arc<dyn DiaryStore>) -> Router {
+    crate::app(Arc::new(config), pool, store, Arc::new(LogMailer))
+}
+
+/// Sends `request` through `app` as if it came from `peer`.
+pub async fn send_from(app: &Router, peer: &str, mut request: Request<Body>) -> Response {
+    let peer: SocketAddr = peer.parse().expect("peer is a socket address");
+    request.extensions_mut().insert(ConnectInfo(peer));
+    app.clone()
+        .oneshot(request)
+        .await
+        .expect("router is infallible")
+}
+
+pub async fn send(app: &Router, request: Request<Body>) -> Response {
+    send_from(app, "127.0.0.1:40000", request).await
+}
+
+/// A JSON `POST` to `uri`.
+pub fn post(uri: &str, body: serde_json::Value) -> Request<Body> {
+    Request::post(uri)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("request is valid")
+}
+
+pub fn get(uri: &str) -> Request<Body> {
+    Request::get(uri)
+        .body(Body::empty())
+        .expect("request is valid")
+}
+
+pub async fn body_bytes(response: Response) -> Vec<u8> {
+    hyper::body::to_bytes(response.into_body())
+        .await
+        .expect("body is readable")
+        .to_vec()
+}
+
+pub async fn body_json(response: Response) -> serde_json::Value {
+    serde_json::from_slice(&body_bytes(response).await).expect("body is JSON")
+}
+
+/// Keeps every message instead of sending it.
+#[derive(Debug, Default)]
+pub struct RecordingMailer {
+    sent: Mutex<Vec<(String, String, String)>>,
+}
+
+impl RecordingMailer {
+    /// The `(to, subject, body)` of every message so far.
+    pub fn sent(&self) -> Vec<(String, String, String)> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[axum::async_trait]
+impl Mailer for RecordingMailer {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> anyhow::Result<()> {
+        self.sent
+            .lock()
+            .unwrap()
+            .push((to.to_string(), subject.to_string(), body.to_string()));
+        Ok(())
+    }
+}
+
+/// The value of query parameter `name` in the first link of `body` carrying it.
+pub fn link_param(body: &str, name: &str) -> Option<String> {
+    let start = body.find(&format!("{}=", name))? + name.len() + 1;
+    Some(
+        body[start..]
+            .split(|c: char| c == '&' || c.is_whitespace())
+            .next()?
+            .to_string(),
+    )
+}
+
+pub const OIDC_ISSUER: &str = "https://sso.school.example";
+pub const OIDC_AUDIENCE: &str = "opendiary";
+
+/// `OIDC_*` settings trusting the key in `testdata/oidc/signing_key.pem`.
+pub fn oidc_vars() -> [(&'static str, &'static str); 3] {
+    [
+        ("OIDC_ISSUER", OIDC_ISSUER),
+        ("OIDC_AUDIENCE", OIDC_AUDIENCE),
+        (
+            "OIDC_JWKS_PATH",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/oidc/jwks.json"),
+        ),
+    ]
+}
+
+/// An id token for `sub` issued by `iss`, signed with the trusted test key or, unless
+/// `trusted`, with another key under the same key id.
+pub fn id_token(iss: &str, sub: &str, trusted: bool) -> String {
+    let pem = if trusted {
+        include_bytes!("../testdata/oidc/signing_key.pem").as_slice()
+    } else {
+        include_bytes!("../testdata/oidc/untrusted_key.pem").as_slice()
+    };
+    let header = jsonwebtoken::Header {
+        kid: Some("test-key".to_string()),
+        ..jsonwebtoken::Header::new(jsonwebtoken::Algorithm::ES256)
+    };
+    let claims = serde_json::json!({
+        "iss": iss,
+        "sub": sub,
+        "aud": OIDC_AUDIENCE,
+        "exp": (Utc::now() + chrono::Duration::minutes(5)).timestamp(),
+    });
+    jsonwebtoken::encode(
+        &header,
+        &claims,
+        &jsonwebtoken::EncodingKey::from_ec_pem(pem).expect("test key is valid"),
+    )
+    .expect("id token is signed")
+}
+
+static LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+static CAPTURE_LOGS: Once = Once::new();
+
+struct CaptureLogger;
+
+impl log::Log for CaptureLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Warn
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            LOGS.lock()
+                .unwrap()
+                .push(format!("{} {}", record.level(), record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Starts recording warnings and errors logged by any test.
+pub fn capture_logs() {
+    CAPTURE_LOGS.call_once(|| {
+        log::set_logger(&CaptureLogger).expect("no other logger is installed");
+        log::set_max_level(log::LevelFilter::Warn);
+    });
+}
+
+/// Whether a warning or error containing `needle` was logged since `capture_logs`.
+pub fn logged(needle: &str) -> bool {
+    LOGS.lock()
+        .unwrap()
+        .iter()
+        .any(|line| line.contains(needle))
+}
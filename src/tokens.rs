@@ -0,0 +1,433 @@
+use crate::auth::{
+    authenticated_session, AuthResult, ClientInfo, EnsureSession, SessionBasedResponse,
+};
+use crate::config::Config;
+use crate::err::JsonBody;
+use crate::ratelimit::SessionBudget;
+use crate::roles::{Admin, RequireRole};
+use crate::{breaks, proceeds, Error, Payload};
+
+use axum::Extension;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use jsonwebtoken::{decode, decode_header, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_with::skip_serializing_none;
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Read-only capabilities a scoped token may carry.
+pub const SCOPED_CAPABILITIES: &[&str] = &["profile.read", "diary.read"];
+
+/// `typ` claim of access tokens, keeping scoped tokens from being used as one.
+pub const ACCESS_TOKEN_TYPE: &str = "access";
+pub const EMAIL_VERIFICATION_TYPE: &str = "email_verification";
+
+pub async fn mint_scoped_token(
+    client: ClientInfo,
+    JsonBody(EnsureSession { ssid, value }): JsonBody<EnsureSession<MintScopedToken>>,
+    Extension(pg): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(budget): Extension<SessionBudget>,
+) -> Payload<SessionBasedResponse<ScopedToken>> {
+    if value.scopes.is_empty() {
+        return breaks(Error::InvalidPayload {
+            message: "`scopes` parameter was empty".to_string(),
+        });
+    }
+    if let Some(scope) = value
+        .scopes
+        .iter()
+        .find(|scope| !SCOPED_CAPABILITIES.contains(&scope.as_str()))
+    {
+        return breaks(Error::InvalidPayload {
+            message: format!("Unknown or non read-only scope `{}`", scope),
+        });
+    }
+
+    let session = match authenticated_session(Some(ssid), &client, &pg, &config, &budget).await? {
+        Some(session) => session,
+        None => {
+            return proceeds(SessionBasedResponse {
+                auth_result: AuthResult::InvalidSession,
+                value: None,
+            })
+        }
+    };
+
+    let now = Utc::now();
+    // never outlive the session the token was minted from
+    let expires_at =
+        (now + Duration::minutes(config.scoped_token_ttl_minutes)).min(session.expires_at);
+    let claims = ScopedClaims {
+        sub: session.belongs_to,
+        scopes: value.scopes,
+        iat: now.timestamp(),
+        exp: expires_at.timestamp(),
+    };
+    let token = sign(&claims, &config)?;
+
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(ScopedToken {
+            token,
+            student_id: claims.sub,
+            scopes: claims.scopes,
+            expires_at,
+        }),
+    })
+}
+
+pub async fn introspect_scoped_token(
+    JsonBody(IntrospectScopedToken { token }): JsonBody<IntrospectScopedToken>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Payload<IntrospectedToken> {
+    proceeds(match verify_scoped_token(&token, &config) {
+        Ok(claims) => IntrospectedToken {
+            valid: true,
+            student_id: Some(claims.sub),
+            scopes: claims.scopes,
+            expires_at: Utc.timestamp_opt(claims.exp, 0).single(),
+        },
+        Err(_) => IntrospectedToken {
+            valid: false,
+            student_id: None,
+            scopes: Vec::new(),
+            expires_at: None,
+        },
+    })
+}
+
+/// Reports which key new tokens are signed with and which retired keys still verify,
+/// so admins can tell when a rotation has been rolled out. Secrets are never included.
+pub async fn report_signing_keys(
+    _admin: RequireRole<Admin>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Payload<SessionBasedResponse<SigningKeys>> {
+    let mut previous_key_ids: Vec<String> = config.token_previous_keys.0.keys().cloned().collect();
+    previous_key_ids.sort();
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(SigningKeys {
+            active_key_id: config.token_signing_key_id.clone(),
+            previous_key_ids,
+        }),
+    })
+}
+
+/// Checks the signature and expiry of a scoped token without touching the database.
+pub fn verify_scoped_token(token: &str, config: &Config) -> Result<ScopedClaims, Error> {
+    verify(token, config)
+}
+
+/// Mints a short-lived access token, which stands in for an ssid when `AUTH_MODE=jwt`.
+pub fn mint_access_token(
+    student_id: Uuid,
+    config: &Config,
+) -> Result<(String, DateTime<Utc>), Error> {
+    let now = Utc::now();
+    let expires_at = now + Duration::minutes(config.access_token_ttl_minutes);
+    let claims = AccessClaims {
+        sub: student_id,
+        typ: ACCESS_TOKEN_TYPE.to_string(),
+        iat: now.timestamp(),
+        exp: expires_at.timestamp(),
+    };
+    Ok((sign(&claims, config)?, expires_at))
+}
+
+/// Checks the signature and expiry of an access token without touching the database.
+pub fn verify_access_token(token: &str, config: &Config) -> Result<AccessClaims, Error> {
+    let claims = verify::<AccessClaims>(token, config)?;
+    if claims.typ != ACCESS_TOKEN_TYPE {
+        return Err(Error::InvalidPayload {
+            message: "Token is not an access token".to_string(),
+        });
+    }
+    Ok(claims)
+}
+
+/// Mints the token of an email verification link, valid only while the account keeps that email.
+pub fn mint_email_verification_token(
+    student_id: Uuid,
+    email: &str,
+    config: &Config,
+) -> Result<String, Error> {
+    let now = Utc::now();
+    let claims = EmailVerificationClaims {
+        sub: student_id,
+        email: email.to_string(),
+        typ: EMAIL_VERIFICATION_TYPE.to_string(),
+        iat: now.timestamp(),
+        exp: (now + Duration::hours(config.email_verification_ttl_hours)).timestamp(),
+    };
+    sign(&claims, config)
+}
+
+pub fn verify_email_verification_token(
+    token: &str,
+    config: &Config,
+) -> Result<EmailVerificationClaims, Error> {
+    let claims = verify::<EmailVerificationClaims>(token, config)?;
+    if claims.typ != EMAIL_VERIFICATION_TYPE {
+        return Err(Error::InvalidPayload {
+            message: "Token is not an email verification token".to_string(),
+        });
+    }
+    Ok(claims)
+}
+
+/// Signs with the active key, naming it in the `kid` header.
+fn sign<C: Serialize>(claims: &C, config: &Config) -> Result<String, Error> {
+    let header = Header {
+        kid: Some(config.token_signing_key_id.clone()),
+        ..Header::default()
+    };
+    Ok(encode(
+        &header,
+        claims,
+        &EncodingKey::from_secret(&config.token_signing_secret),
+    )?)
+}
+
+/// Verifies against the key named by `kid`, so tokens signed with a previous key
+/// from `TOKEN_PREVIOUS_SECRETS` still verify.
+fn verify<C: DeserializeOwned>(token: &str, config: &Config) -> Result<C, Error> {
+    let mut validation = Validation::default();
+    validation.leeway = 0;
+    let secret =
+        match decode_header(token)?.kid {
+            Some(kid) if kid != config.token_signing_key_id => config
+                .token_previous_keys
+                .0
+                .get(&kid)
+                .ok_or_else(|| Error::InvalidPayload {
+                    message: format!("Token was signed with unknown key `{}`", kid),
+                })?,
+            _ => &config.token_signing_secret,
+        };
+    let data = decode::<C>(token, &DecodingKey::from_secret(secret), &validation)?;
+    Ok(data.claims)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScopedClaims {
+    pub sub: Uuid,
+    pub scopes: Vec<String>,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessClaims {
+    pub sub: Uuid,
+    pub typ: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailVerificationClaims {
+    pub sub: Uuid,
+    pub email: String,
+    pub typ: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MintScopedToken {
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScopedToken {
+    pub token: String,
+    pub student_id: Uuid,
+    pub scopes: Vec<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IntrospectScopedToken {
+    pub token: String,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize)]
+pub struct IntrospectedToken {
+    pub valid: bool,
+    pub student_id: Option<Uuid>,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SigningKeys {
+    pub active_key_id: String,
+    pub previous_key_ids: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn scoped_claims(exp: DateTime<Utc>) -> ScopedClaims {
+        ScopedClaims {
+            sub: Uuid::new_v4(),
+            scopes: vec!["diary.read".to_string()],
+            iat: Utc::now().timestamp(),
+            exp: exp.timestamp(),
+        }
+    }
+
+    #[tokio::test]
+    async fn mints_a_verifiable_token_from_a_session() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let config = Arc::new(testing::config(&[("SCOPED_TOKEN_TTL_MINUTES", "5")]));
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let ssid = testing::insert_session(&pool, student, Utc::now() + Duration::days(1)).await;
+
+        let response = testing::json(
+            mint_scoped_token(
+                ClientInfo::default(),
+                JsonBody(EnsureSession {
+                    ssid,
+                    value: MintScopedToken {
+                        scopes: vec!["diary.read".to_string()],
+                    },
+                }),
+                Extension(pool.clone()),
+                Extension(config.clone()),
+                Extension(SessionBudget::default()),
+            )
+            .await,
+        );
+
+        assert_eq!(response["auth_result"], "Success");
+        let claims = verify_scoped_token(response["token"].as_str().unwrap(), &config).unwrap();
+        assert_eq!(claims.sub, student);
+        assert_eq!(claims.scopes, vec!["diary.read"]);
+        assert!(claims.exp <= (Utc::now() + Duration::minutes(5)).timestamp());
+    }
+
+    #[tokio::test]
+    async fn refuses_scopes_that_are_not_read_only() {
+        let config = Arc::new(testing::config(&[]));
+
+        let response = testing::json(
+            mint_scoped_token(
+                ClientInfo::default(),
+                JsonBody(EnsureSession {
+                    ssid: "unused".to_string(),
+                    value: MintScopedToken {
+                        scopes: vec!["diary.write".to_string()],
+                    },
+                }),
+                Extension(testing::lazy_pool()),
+                Extension(config),
+                Extension(SessionBudget::default()),
+            )
+            .await,
+        );
+
+        assert_eq!(response["error"], "InvalidPayload");
+    }
+
+    #[test]
+    fn rejects_expired_and_tampered_tokens() {
+        let config = testing::config(&[]);
+
+        let expired = sign(&scoped_claims(Utc::now() - Duration::seconds(1)), &config).unwrap();
+        assert!(verify_scoped_token(&expired, &config).is_err());
+
+        let valid = sign(&scoped_claims(Utc::now() + Duration::minutes(5)), &config).unwrap();
+        assert!(verify_scoped_token(&valid, &config).is_ok());
+        let other = testing::config(&[(
+            "TOKEN_SIGNING_SECRET",
+            "some-other-secret-that-is-long-enough",
+        )]);
+        assert!(verify_scoped_token(&valid, &other).is_err());
+    }
+
+    #[test]
+    fn verifies_tokens_of_previous_keys_after_rotation() {
+        let old_secret = "the-old-signing-secret-that-is-long-enough";
+        let before = testing::config(&[
+            ("TOKEN_SIGNING_KEY_ID", "2025"),
+            ("TOKEN_SIGNING_SECRET", old_secret),
+        ]);
+        let old_token = sign(&scoped_claims(Utc::now() + Duration::minutes(5)), &before).unwrap();
+
+        let previous = format!("2025:{}", old_secret);
+        let after = testing::config(&[
+            ("TOKEN_SIGNING_KEY_ID", "2026"),
+            ("TOKEN_PREVIOUS_SECRETS", &previous),
+        ]);
+        let new_token = sign(&scoped_claims(Utc::now() + Duration::minutes(5)), &after).unwrap();
+
+        assert_eq!(
+            decode_header(&new_token).unwrap().kid.as_deref(),
+            Some("2026")
+        );
+        assert!(verify_scoped_token(&new_token, &after).is_ok());
+        assert!(verify_scoped_token(&old_token, &after).is_ok());
+        // the old key alone does not know the new one
+        assert!(verify_scoped_token(&new_token, &before).is_err());
+
+        let retired = testing::config(&[("TOKEN_SIGNING_KEY_ID", "2026")]);
+        assert!(verify_scoped_token(&old_token, &retired).is_err());
+    }
+
+    #[tokio::test]
+    async fn reports_the_active_key_to_admins_only() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let admin = testing::insert_user(&pool, "admin", "password").await;
+        testing::set_role(&pool, admin, crate::models::Role::Admin).await;
+        let admin_ssid =
+            testing::insert_session(&pool, admin, Utc::now() + Duration::hours(1)).await;
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let student_ssid =
+            testing::insert_session(&pool, student, Utc::now() + Duration::hours(1)).await;
+        let config = testing::config(&[
+            ("TOKEN_SIGNING_KEY_ID", "2026"),
+            (
+                "TOKEN_PREVIOUS_SECRETS",
+                "2025:the-old-signing-secret-that-is-long-enough,2024:an-even-older-secret-that-is-long-enough",
+            ),
+        ]);
+        let app = testing::app(config, pool).await;
+
+        let response = testing::send(
+            &app,
+            testing::post(
+                "/admin/signing_keys",
+                serde_json::json!({ "ssid": admin_ssid }),
+            ),
+        )
+        .await;
+        let body = testing::body_json(response).await;
+        assert_eq!(body["active_key_id"], "2026");
+        assert_eq!(
+            body["previous_key_ids"],
+            serde_json::json!(["2024", "2025"])
+        );
+        assert!(!body.to_string().contains("secret"));
+
+        let response = testing::send(
+            &app,
+            testing::post(
+                "/admin/signing_keys",
+                serde_json::json!({ "ssid": student_ssid }),
+            ),
+        )
+        .await;
+        assert_eq!(response.status(), axum::http::StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,634 @@
+use crate::auth::{
+    authenticated_session, AuthResult, ClientInfo, EnsureSession, SessionBasedResponse,
+};
+use crate::config::Config;
+use crate::err::JsonBody;
+use crate::lockout::{check_lockout, record_login_failure};
+use crate::models::StudentTotp;
+use crate::ratelimit::SessionBudget;
+use crate::{breaks, proceeds, Error, Payload};
+
+use axum::Extension;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+const TOTP_STEP_SECS: i64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// Steps either side of the current one still accepted, to absorb clock drift.
+const TOTP_SKEW_STEPS: i64 = 1;
+const TOTP_SECRET_LEN: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Starts enrollment with a fresh secret. Two-factor authentication is only enforced
+/// once a code for it has been confirmed.
+pub async fn enroll_totp(
+    client: ClientInfo,
+    JsonBody(EnsureSession { ssid, .. }): JsonBody<EnsureSession<EnrollTotp>>,
+    Extension(pg): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(budget): Extension<SessionBudget>,
+) -> Payload<SessionBasedResponse<TotpEnrollment>> {
+    let session = match authenticated_session(Some(ssid), &client, &pg, &config, &budget).await? {
+        Some(session) => session,
+        None => return invalid_session(),
+    };
+    if find_totp(session.belongs_to, &pg)
+        .await?
+        .is_some_and(|totp| totp.confirmed)
+    {
+        return breaks(Error::InvalidPayload {
+            message: "Two-factor authentication is already enabled".to_string(),
+        });
+    }
+
+    let secret: [u8; TOTP_SECRET_LEN] = thread_rng().gen();
+    sqlx::query(
+        "INSERT INTO user_totp (belongs_to, secret, confirmed, created_at) VALUES ($1, $2, false, $3) \
+        ON CONFLICT (belongs_to) DO UPDATE SET secret = $2, created_at = $3",
+    )
+    .bind(session.belongs_to)
+    .bind(hex::encode(secret))
+    .bind(Utc::now())
+    .execute(&pg)
+    .await
+    .map_err(Error::from)?;
+
+    let username = sqlx::query_scalar::<_, String>("SELECT username FROM users WHERE uuid = $1")
+        .bind(session.belongs_to)
+        .fetch_one(&pg)
+        .await
+        .map_err(Error::from)?;
+    let encoded = base32(&secret);
+    let otpauth_uri = format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+        issuer = percent_encode(&config.totp_issuer),
+        account = percent_encode(&username),
+        secret = encoded,
+        digits = TOTP_DIGITS,
+        period = TOTP_STEP_SECS,
+    );
+
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(TotpEnrollment {
+            secret: encoded,
+            otpauth_uri,
+        }),
+    })
+}
+
+/// Confirms enrollment with a code from the authenticator, enabling two-factor
+/// authentication and handing out one-time recovery codes.
+pub async fn confirm_totp(
+    client: ClientInfo,
+    JsonBody(EnsureSession { ssid, value }): JsonBody<EnsureSession<TotpCode>>,
+    Extension(pg): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(budget): Extension<SessionBudget>,
+) -> Payload<SessionBasedResponse<TotpEnabled>> {
+    let session = match authenticated_session(Some(ssid), &client, &pg, &config, &budget).await? {
+        Some(session) => session,
+        None => return invalid_session(),
+    };
+    let totp = match find_totp(session.belongs_to, &pg).await? {
+        Some(totp) if !totp.confirmed => totp,
+        Some(_) => {
+            return breaks(Error::InvalidPayload {
+                message: "Two-factor authentication is already enabled".to_string(),
+            })
+        }
+        None => {
+            return breaks(Error::InvalidPayload {
+                message: "Two-factor authentication enrollment was not started".to_string(),
+            })
+        }
+    };
+    if !consume_totp(&totp, &value.code, &pg).await? {
+        return breaks(invalid_code());
+    }
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| generate_recovery_code())
+        .collect();
+    let mut tx = pg.begin().await.map_err(Error::from)?;
+    sqlx::query("UPDATE user_totp SET confirmed = true WHERE belongs_to = $1")
+        .bind(session.belongs_to)
+        .execute(&mut tx)
+        .await
+        .map_err(Error::from)?;
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE belongs_to = $1")
+        .bind(session.belongs_to)
+        .execute(&mut tx)
+        .await
+        .map_err(Error::from)?;
+    for code in &recovery_codes {
+        sqlx::query("INSERT INTO totp_recovery_codes (belongs_to, code_hash) VALUES ($1, $2)")
+            .bind(session.belongs_to)
+            .bind(hash_recovery_code(code))
+            .execute(&mut tx)
+            .await
+            .map_err(Error::from)?;
+    }
+    tx.commit().await.map_err(Error::from)?;
+
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(TotpEnabled { recovery_codes }),
+    })
+}
+
+/// Disables two-factor authentication, which takes a current TOTP or recovery code.
+///
+/// Wrong codes count as failed logins, so guessing them locks the account, and this
+/// endpoint with it, just like guessing at login would.
+pub async fn disable_totp(
+    client: ClientInfo,
+    JsonBody(EnsureSession { ssid, value }): JsonBody<EnsureSession<TotpCode>>,
+    Extension(pg): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(budget): Extension<SessionBudget>,
+) -> Payload<SessionBasedResponse<TotpDisabled>> {
+    let session = match authenticated_session(Some(ssid), &client, &pg, &config, &budget).await? {
+        Some(session) => session,
+        None => return invalid_session(),
+    };
+    if let Err(err) = check_lockout(session.belongs_to, &client, &pg).await {
+        return breaks(err);
+    }
+    if let Err(err) = check_second_factor(session.belongs_to, Some(&value.code), &pg).await {
+        if matches!(err, Error::AuthenticationFailure { .. }) {
+            record_login_failure(session.belongs_to, &client, &pg, &config).await?;
+        }
+        return breaks(err);
+    }
+
+    let mut tx = pg.begin().await.map_err(Error::from)?;
+    sqlx::query("DELETE FROM user_totp WHERE belongs_to = $1")
+        .bind(session.belongs_to)
+        .execute(&mut tx)
+        .await
+        .map_err(Error::from)?;
+    sqlx::query("DELETE FROM totp_recovery_codes WHERE belongs_to = $1")
+        .bind(session.belongs_to)
+        .execute(&mut tx)
+        .await
+        .map_err(Error::from)?;
+    tx.commit().await.map_err(Error::from)?;
+
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::Success,
+        value: Some(TotpDisabled {
+            student_id: session.belongs_to,
+        }),
+    })
+}
+
+/// Passes students without confirmed two-factor authentication. Everyone else needs a
+/// current TOTP code or an unused recovery code, which is used up.
+pub async fn check_second_factor(
+    student_id: Uuid,
+    code: Option<&str>,
+    pg: &PgPool,
+) -> Result<(), Error> {
+    let totp = match find_totp(student_id, pg).await? {
+        Some(totp) if totp.confirmed => totp,
+        _ => return Ok(()),
+    };
+    let code = match code.map(str::trim) {
+        Some(code) if !code.is_empty() => code,
+        _ => {
+            return Err(Error::TwoFactorRequired {
+                message: "A two-factor authentication code is required".to_string(),
+            })
+        }
+    };
+
+    if consume_totp(&totp, code, pg).await? {
+        return Ok(());
+    }
+    let used =
+        sqlx::query("DELETE FROM totp_recovery_codes WHERE belongs_to = $1 AND code_hash = $2")
+            .bind(student_id)
+            .bind(hash_recovery_code(code))
+            .execute(pg)
+            .await
+            .map_err(Error::from)?;
+    if used.rows_affected() == 1 {
+        log::info!("Student {} used a recovery code", student_id);
+        Ok(())
+    } else {
+        Err(invalid_code())
+    }
+}
+
+async fn find_totp(student_id: Uuid, pg: &PgPool) -> Result<Option<StudentTotp>, Error> {
+    sqlx::query_as::<_, StudentTotp>(
+        "SELECT belongs_to, secret, confirmed, last_used_step, created_at FROM user_totp \
+        WHERE belongs_to = $1",
+    )
+    .bind(student_id)
+    .fetch_optional(pg)
+    .await
+    .map_err(Error::from)
+}
+
+/// Checks a TOTP code, recording its time step so the same code cannot be replayed.
+async fn consume_totp(totp: &StudentTotp, code: &str, pg: &PgPool) -> Result<bool, Error> {
+    let secret = hex::decode(&totp.secret).map_err(|err| Error::InternalError {
+        kind: "CryptoError",
+        message: format!("Stored TOTP secret is corrupt: {}", err),
+    })?;
+    let step = match matching_step(&secret, code, Utc::now().timestamp(), totp.last_used_step) {
+        Some(step) => step,
+        None => return Ok(false),
+    };
+    // a concurrent login may have used this step in the meantime
+    let recorded = sqlx::query(
+        "UPDATE user_totp SET last_used_step = $2 \
+        WHERE belongs_to = $1 AND (last_used_step IS NULL OR last_used_step < $2)",
+    )
+    .bind(totp.belongs_to)
+    .bind(step)
+    .execute(pg)
+    .await
+    .map_err(Error::from)?;
+    Ok(recorded.rows_affected() == 1)
+}
+
+/// Finds the time step around `now` that `code` was generated for, skipping steps
+/// up to `last_used`.
+fn matching_step(secret: &[u8], code: &str, now: i64, last_used: Option<i64>) -> Option<i64> {
+    if code.len() != TOTP_DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let current = now / TOTP_STEP_SECS;
+    (current - TOTP_SKEW_STEPS..=current + TOTP_SKEW_STEPS)
+        .filter(|step| last_used.is_none_or(|last| *step > last))
+        .find(|step| hotp(secret, *step as u64) == code)
+}
+
+/// RFC 4226 HOTP value of `secret` for `counter`.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    truncated % 10u32.pow(TOTP_DIGITS)
+}
+
+fn generate_recovery_code() -> String {
+    let mut rng = thread_rng();
+    let chars: String = (0..10)
+        .map(|_| RECOVERY_CODE_ALPHABET[rng.gen_range(0..RECOVERY_CODE_ALPHABET.len())] as char)
+        .collect();
+    format!("{}-{}", &chars[..5], &chars[5..])
+}
+
+/// Recovery codes are stored hashed, ignoring case and the separating dash.
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| *c != '-')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    let mut hasher: Sha256 = Digest::new();
+    hasher.update(normalized.as_bytes());
+    hex::encode(hasher.finalize())
+}
+
+/// Unpadded RFC 4648 base32, the encoding authenticator apps expect secrets in.
+fn base32(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn invalid_code() -> Error {
+    Error::AuthenticationFailure {
+        message: "Invalid two-factor authentication code".to_string(),
+    }
+}
+
+fn invalid_session<V: Serialize>() -> Payload<SessionBasedResponse<V>> {
+    proceeds(SessionBasedResponse {
+        auth_result: AuthResult::InvalidSession,
+        value: None,
+    })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct EnrollTotp {}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TotpCode {
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TotpEnrollment {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TotpEnabled {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TotpDisabled {
+    student_id: Uuid,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    use serde_json::json;
+
+    /// The code `secret` shows `offset` steps from now.
+    fn code_at(secret: &[u8], offset: i64) -> String {
+        let step = Utc::now().timestamp() / TOTP_STEP_SECS + offset;
+        format!("{:06}", hotp(secret, step as u64))
+    }
+
+    async fn stored_secret(pool: &PgPool, student: Uuid) -> Vec<u8> {
+        let secret =
+            sqlx::query_scalar::<_, String>("SELECT secret FROM user_totp WHERE belongs_to = $1")
+                .bind(student)
+                .fetch_one(pool)
+                .await
+                .unwrap();
+        hex::decode(secret).unwrap()
+    }
+
+    /// Lets the codes of steps used so far be accepted again.
+    async fn forget_used_steps(pool: &PgPool, student: Uuid) {
+        sqlx::query("UPDATE user_totp SET last_used_step = NULL WHERE belongs_to = $1")
+            .bind(student)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn failures_of(pool: &PgPool, student: Uuid) -> i32 {
+        sqlx::query_scalar::<_, i32>("SELECT failures FROM login_failures WHERE belongs_to = $1")
+            .bind(student)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+            .unwrap_or(0)
+    }
+
+    async fn post(app: &axum::Router, uri: &str, body: serde_json::Value) -> serde_json::Value {
+        testing::body_json(testing::send(app, testing::post(uri, body)).await).await
+    }
+
+    /// Enrolls `student` and confirms it, returning the secret and the recovery codes.
+    async fn enable(
+        app: &axum::Router,
+        pool: &PgPool,
+        student: Uuid,
+        ssid: &str,
+    ) -> (Vec<u8>, Vec<String>) {
+        let enrollment = post(app, "/student/2fa/enroll", json!({ "ssid": ssid })).await;
+        assert_eq!(enrollment["auth_result"], "Success");
+        let secret = stored_secret(pool, student).await;
+        assert_eq!(enrollment["secret"], base32(&secret));
+
+        let confirmed = post(
+            app,
+            "/student/2fa/confirm",
+            json!({ "ssid": ssid, "code": code_at(&secret, 0) }),
+        )
+        .await;
+        assert_eq!(confirmed["auth_result"], "Success");
+        let recovery_codes = confirmed["recovery_codes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|code| code.as_str().unwrap().to_string())
+            .collect();
+        forget_used_steps(pool, student).await;
+        (secret, recovery_codes)
+    }
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        let secret = b"12345678901234567890";
+        let expected = [755224, 287082, 359152, 969429, 338314];
+
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(secret, counter as u64), *code);
+        }
+    }
+
+    #[test]
+    fn base32_matches_rfc_4648() {
+        assert_eq!(base32(b""), "");
+        assert_eq!(base32(b"f"), "MY");
+        assert_eq!(base32(b"foob"), "MZXW6YQ");
+        assert_eq!(base32(b"foobar"), "MZXW6YTBOI");
+    }
+
+    #[test]
+    fn accepts_codes_of_nearby_steps_once() {
+        let secret = b"12345678901234567890";
+        let now = 1_000 * TOTP_STEP_SECS;
+        let code = |step: i64| format!("{:06}", hotp(secret, step as u64));
+
+        assert_eq!(matching_step(secret, &code(1_000), now, None), Some(1_000));
+        assert_eq!(matching_step(secret, &code(999), now, None), Some(999));
+        assert_eq!(matching_step(secret, &code(1_001), now, None), Some(1_001));
+        assert_eq!(matching_step(secret, &code(1_002), now, None), None);
+        assert_eq!(matching_step(secret, &code(1_000), now, Some(1_000)), None);
+        assert_eq!(matching_step(secret, "12345", now, None), None);
+    }
+
+    #[test]
+    fn recovery_codes_ignore_case_and_dashes() {
+        let code = generate_recovery_code();
+
+        assert_eq!(code.len(), 11);
+        assert_eq!(
+            hash_recovery_code(&code),
+            hash_recovery_code(&code.replace('-', "").to_uppercase())
+        );
+    }
+
+    #[tokio::test]
+    async fn logins_need_a_code_once_confirmed() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let ssid =
+            testing::insert_session(&pool, student, Utc::now() + chrono::Duration::hours(1)).await;
+        let app = testing::app(testing::config(&[]), pool.clone()).await;
+        let login = |totp_code: Option<String>| {
+            post(
+                &app,
+                "/session/login",
+                json!({ "uuid": student, "password": "password", "totp_code": totp_code }),
+            )
+        };
+
+        // enrolling alone changes nothing
+        post(&app, "/student/2fa/enroll", json!({ "ssid": ssid })).await;
+        assert_eq!(login(None).await["success"], true);
+
+        let (secret, _) = enable(&app, &pool, student, &ssid).await;
+        assert_eq!(login(None).await["error"], "TwoFactorRequired");
+        assert_eq!(
+            login(Some("000000".to_string())).await["error"],
+            "AuthenticationFailure"
+        );
+        assert_eq!(failures_of(&pool, student).await, 1);
+
+        let code = code_at(&secret, 0);
+        assert_eq!(login(Some(code.clone())).await["success"], true);
+        assert_eq!(failures_of(&pool, student).await, 0);
+        // a code is only good for one login
+        assert_eq!(login(Some(code)).await["error"], "AuthenticationFailure");
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_are_used_up() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let ssid =
+            testing::insert_session(&pool, student, Utc::now() + chrono::Duration::hours(1)).await;
+        let app = testing::app(testing::config(&[]), pool.clone()).await;
+        let (_, recovery_codes) = enable(&app, &pool, student, &ssid).await;
+        assert_eq!(recovery_codes.len(), RECOVERY_CODE_COUNT);
+        let login = |totp_code: &str| {
+            post(
+                &app,
+                "/session/login",
+                json!({ "uuid": student, "password": "password", "totp_code": totp_code }),
+            )
+        };
+
+        assert_eq!(login(&recovery_codes[0]).await["success"], true);
+        assert_eq!(
+            login(&recovery_codes[0]).await["error"],
+            "AuthenticationFailure"
+        );
+        assert_eq!(login(&recovery_codes[1]).await["success"], true);
+    }
+
+    #[tokio::test]
+    async fn disabling_takes_a_code_and_counts_wrong_ones() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let ssid =
+            testing::insert_session(&pool, student, Utc::now() + chrono::Duration::hours(1)).await;
+        let app = testing::app(testing::config(&[("LOCKOUT_THRESHOLD", "2")]), pool.clone()).await;
+        let (secret, _) = enable(&app, &pool, student, &ssid).await;
+        let disable = |code: String| {
+            post(
+                &app,
+                "/student/2fa/disable",
+                json!({ "ssid": ssid, "code": code }),
+            )
+        };
+
+        assert_eq!(
+            disable("000000".to_string()).await["error"],
+            "AuthenticationFailure"
+        );
+        assert_eq!(failures_of(&pool, student).await, 1);
+        assert_eq!(
+            disable("000001".to_string()).await["error"],
+            "AuthenticationFailure"
+        );
+        // locked now, so not even the right code gets through
+        assert_eq!(disable(code_at(&secret, 0)).await["error"], "AccountLocked");
+
+        crate::lockout::lift_lockouts(student, &pool).await.unwrap();
+        let body = disable(code_at(&secret, 0)).await;
+        assert_eq!(body["auth_result"], "Success");
+        assert_eq!(body["student_id"], student.to_string());
+        assert!(find_totp(student, &pool).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn single_sign_on_needs_a_code_too() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let student = testing::insert_user(&pool, "student", "password").await;
+        let ssid =
+            testing::insert_session(&pool, student, Utc::now() + chrono::Duration::hours(1)).await;
+        let app = testing::app(testing::config(&testing::oidc_vars()), pool.clone()).await;
+        let id_token = testing::id_token(testing::OIDC_ISSUER, "pupil-42", true);
+        let linked = post(
+            &app,
+            "/student/link_sso",
+            json!({ "ssid": ssid, "id_token": id_token }),
+        )
+        .await;
+        assert_eq!(linked["auth_result"], "Success");
+        let (secret, _) = enable(&app, &pool, student, &ssid).await;
+        let login = |totp_code: Option<String>| {
+            post(
+                &app,
+                "/student/login_sso",
+                json!({ "id_token": id_token, "totp_code": totp_code }),
+            )
+        };
+
+        assert_eq!(login(None).await["error"], "TwoFactorRequired");
+        assert_eq!(
+            login(Some("000000".to_string())).await["error"],
+            "AuthenticationFailure"
+        );
+        assert_eq!(failures_of(&pool, student).await, 1);
+        assert_eq!(login(Some(code_at(&secret, 0))).await["success"], true);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::config::Config;
+use crate::mail::Mailer;
+use crate::models::UserData;
+use crate::tokens::{mint_email_verification_token, verify_email_verification_token};
+use crate::{breaks, proceeds, Error, Payload};
+
+use axum::extract::Query;
+use axum::Extension;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Emails a signed verification link for the student's current address. Failures are
+/// logged only, the student can get a new link by logging in.
+pub async fn send_verification_email(student: &UserData, mailer: &dyn Mailer, config: &Config) {
+    let token = match mint_email_verification_token(student.uuid, &student.email, config) {
+        Ok(token) => token,
+        Err(err) => {
+            log::error!("Could not mint email verification token: {:?}", err);
+            return;
+        }
+    };
+    let body = format!(
+        "Hello, {}!\n\nConfirm your OpenDiary email address by opening the link below. \
+        It expires in {} hours.\n\n{}/student/verify_email?token={}\n",
+        student.name,
+        config.email_verification_ttl_hours,
+        config.public_url.trim_end_matches('/'),
+        token
+    );
+    if let Err(err) = mailer
+        .send(&student.email, "Confirm your OpenDiary email", &body)
+        .await
+    {
+        log::error!("Could not send verification email: {:#}", err);
+    }
+}
+
+/// Whether the student may still log in: verified, verification not required, or still
+/// within `EMAIL_VERIFICATION_GRACE_HOURS` of registering.
+pub fn within_verification_grace(student: &UserData, config: &Config) -> bool {
+    student.email_verified
+        || !config.require_verified_email
+        || Utc::now() < student.created_at + Duration::hours(config.email_verification_grace_hours)
+}
+
+pub async fn verify_email(
+    Query(VerifyEmail { token }): Query<VerifyEmail>,
+    Extension(pg): Extension<PgPool>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Payload<EmailVerified> {
+    let claims = match verify_email_verification_token(&token, &config) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return breaks(Error::AuthenticationFailure {
+                message: "Verification link is invalid or expired".to_string(),
+            })
+        }
+    };
+
+    // links for an address the account no longer uses verify nothing
+    let updated = sqlx::query(
+        "UPDATE users SET email_verified = true WHERE uuid = $1 AND lower(email) = lower($2)",
+    )
+    .bind(claims.sub)
+    .bind(&claims.email)
+    .execute(&pg)
+    .await
+    .map_err(Error::from)?;
+    if updated.rows_affected() == 0 {
+        return breaks(Error::AuthenticationFailure {
+            message: "Verification link does not match this account's email".to_string(),
+        });
+    }
+
+    proceeds(EmailVerified {
+        student_id: claims.sub,
+        email: claims.email,
+    })
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EmailVerified {
+    student_id: Uuid,
+    email: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, RecordingMailer};
+
+    use axum::Router;
+    use serde_json::json;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    async fn register(app: &Router, username: &str) -> Uuid {
+        let response = testing::send(
+            app,
+            testing::post(
+                "/student/register",
+                json!({
+                    "username": username,
+                    "name": "Test",
+                    "surname": "Student",
+                    "email": format!("{}@example.org", username),
+                    "password": "correct horse battery",
+                }),
+            ),
+        )
+        .await;
+        let body = testing::body_json(response).await;
+        body["student_id"]
+            .as_str()
+            .and_then(|id| id.parse().ok())
+            .unwrap_or_else(|| panic!("registration failed: {}", body))
+    }
+
+    async fn login(app: &Router, student: Uuid) -> serde_json::Value {
+        let response = testing::send(
+            app,
+            testing::post(
+                "/session/login",
+                json!({ "uuid": student, "password": "correct horse battery" }),
+            ),
+        )
+        .await;
+        testing::body_json(response).await
+    }
+
+    async fn verify(app: &Router, token: &str) -> serde_json::Value {
+        let uri = format!("/student/verify_email?token={}", token);
+        testing::body_json(testing::send(app, testing::get(&uri)).await).await
+    }
+
+    fn latest_token(mailer: &RecordingMailer) -> String {
+        let sent = mailer.sent();
+        let (_, _, body) = sent.last().expect("a mail was sent");
+        testing::link_param(body, "token").expect("the mail has a link")
+    }
+
+    #[tokio::test]
+    async fn unverified_students_log_in_only_within_the_grace_period() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let mailer = Arc::new(RecordingMailer::default());
+        let within =
+            testing::app_with_mailer(testing::config(&[]), pool.clone(), mailer.clone()).await;
+        let after = testing::app_with_mailer(
+            testing::config(&[("EMAIL_VERIFICATION_GRACE_HOURS", "0")]),
+            pool.clone(),
+            mailer.clone(),
+        )
+        .await;
+
+        let student = register(&within, "student").await;
+        assert_eq!(mailer.sent().len(), 1);
+        assert_eq!(mailer.sent()[0].0, "student@example.org");
+
+        assert_eq!(login(&within, student).await["success"], true);
+        let refused = login(&after, student).await;
+        assert_eq!(refused["error"], "EmailNotVerified");
+        // a fresh link replaces one that may have been lost
+        assert_eq!(mailer.sent().len(), 2);
+
+        let verified = verify(&after, &latest_token(&mailer)).await;
+        assert_eq!(verified["student_id"], student.to_string());
+        assert_eq!(login(&after, student).await["success"], true);
+    }
+
+    #[tokio::test]
+    async fn links_only_verify_the_current_address() {
+        let Some(pool) = testing::pool().await else {
+            return;
+        };
+        let mailer = Arc::new(RecordingMailer::default());
+        let app =
+            testing::app_with_mailer(testing::config(&[]), pool.clone(), mailer.clone()).await;
+        let student = register(&app, "student").await;
+        let token = latest_token(&mailer);
+
+        sqlx::query("UPDATE users SET email = 'changed@example.org' WHERE uuid = $1")
+            .bind(student)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(verify(&app, &token).await["error"], "AuthenticationFailure");
+        assert_eq!(
+            verify(&app, "forged").await["error"],
+            "AuthenticationFailure"
+        );
+        let verified: bool = sqlx::query_scalar("SELECT email_verified FROM users WHERE uuid = $1")
+            .bind(student)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(!verified);
+    }
+}